@@ -1,27 +1,437 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(dead_code)]
 enum Permissions {
     Read,
     Write,
     ReadWrite,
 }
 
+#[allow(dead_code)]
+impl Permissions {
+    fn can_read(&self) -> bool {
+        matches!(self, Permissions::Read | Permissions::ReadWrite)
+    }
+
+    fn can_write(&self) -> bool {
+        matches!(self, Permissions::Write | Permissions::ReadWrite)
+    }
+
+    // Unix mode bits for these permissions. Folders need the search bit alongside read
+    // to be usable.
+    fn to_mode(&self, is_dir: bool) -> u32 {
+        match (self, is_dir) {
+            (Permissions::Read, false) => 0o444,
+            (Permissions::Write, false) => 0o222,
+            (Permissions::ReadWrite, false) => 0o644,
+            (Permissions::Read, true) => 0o555,
+            (Permissions::Write, true) => 0o333,
+            (Permissions::ReadWrite, true) => 0o755,
+        }
+    }
+
+    // Permissions from the owner bits of a unix mode
+    fn from_mode(mode: u32) -> Permissions {
+        match (mode & 0o400 != 0, mode & 0o200 != 0) {
+            (true, true) => Permissions::ReadWrite,
+            (false, true) => Permissions::Write,
+            _ => Permissions::Read,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(dead_code)]
 enum INode {
     Folder {
-        contents: HashMap<String, INode>,
+        contents: HashMap<String, Arc<Mutex<INode>>>,
         permissions: Permissions,
+        times: Timestamps,
+        // Inode number, unique within the process. Numbers are handed out afresh when
+        // a tree is loaded, so they are not saved.
+        #[cfg_attr(feature = "serde", serde(skip, default = "next_ino"))]
+        ino: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        xattrs: HashMap<String, Vec<u8>>,
     },
     File {
-        data: Vec<u8>,
+        // Shared between files only until one of them writes, which copies it first
+        #[cfg_attr(feature = "serde", serde(with = "base64_bytes"))]
+        data: Arc<Vec<u8>>,
+        permissions: Permissions,
+        // Number of directory entries naming this file
+        links: usize,
+        times: Timestamps,
+        #[cfg_attr(feature = "serde", serde(skip, default = "next_ino"))]
+        ino: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        xattrs: HashMap<String, Vec<u8>>,
+        // Earlier contents, newest first, when the file system keeps versions. Only
+        // the current contents are saved or count against the quota.
+        #[cfg_attr(feature = "serde", serde(skip))]
+        history: VecDeque<Version>,
+        // Locks only mean something to running descriptors, so they are never saved
+        #[cfg_attr(feature = "serde", serde(skip))]
+        lock: LockState,
+        // Contents deflated while no descriptor has the file open; `data` is left
+        // empty until the next open inflates them again
+        #[cfg(feature = "compression")]
+        #[cfg_attr(feature = "serde", serde(default))]
+        packed: Option<Packed>,
+    },
+    Symlink {
+        target: String,
         permissions: Permissions,
+        times: Timestamps,
+        #[cfg_attr(feature = "serde", serde(skip, default = "next_ino"))]
+        ino: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        xattrs: HashMap<String, Vec<u8>>,
+    },
+}
+
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Packed {
+    // Size of the contents once inflated
+    len: usize,
+    #[cfg_attr(feature = "serde", serde(with = "base64_bytes"))]
+    bytes: Arc<Vec<u8>>,
+}
+
+#[cfg(feature = "compression")]
+impl Packed {
+    fn new(data: &[u8]) -> io::Result<Packed> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data)?;
+        Ok(Packed {
+            len: data.len(),
+            bytes: Arc::new(encoder.finish()?),
+        })
+    }
+
+    fn unpack(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.len);
+        flate2::read::ZlibDecoder::new(&self.bytes[..]).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Timestamps {
+    created: SystemTime,
+    modified: SystemTime,
+    accessed: SystemTime,
+}
+
+// Advisory lock on a file, taken through descriptors with SimpleFileSystem::lock
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[allow(dead_code)]
+enum LockState {
+    #[default]
+    Unlocked,
+    // Number of descriptors holding a shared lock
+    Shared(usize),
+    Exclusive,
+}
+
+impl LockState {
+    // The state left once one holder of `held` lets go
+    fn without(self, held: Option<LockKind>) -> LockState {
+        match (self, held) {
+            (LockState::Shared(1), Some(LockKind::Shared)) => LockState::Unlocked,
+            (LockState::Shared(n), Some(LockKind::Shared)) => LockState::Shared(n - 1),
+            (LockState::Exclusive, Some(LockKind::Exclusive)) => LockState::Unlocked,
+            (state, _) => state,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+impl Timestamps {
+    fn new(now: SystemTime) -> Self {
+        Timestamps {
+            created: now,
+            modified: now,
+            accessed: now,
+        }
+    }
+}
+
+// Earlier contents of a file, with the time they were last modified
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+struct Version {
+    data: Arc<Vec<u8>>,
+    modified: SystemTime,
+}
+
+// File contents are stored as base64 strings rather than arrays of numbers
+#[cfg(feature = "serde")]
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::sync::Arc;
+
+    pub fn serialize<S: Serializer>(data: &Arc<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(&data[..]))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Arc<Vec<u8>>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let data = STANDARD.decode(encoded).map_err(serde::de::Error::custom)?;
+        Ok(Arc::new(data))
+    }
+}
+
+// Source of the current time, replaceable so tests can control timestamps
+trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Lock a node, reporting a mutex poisoned by a panicking thread as an error rather than
+// panicking too
+fn lock_node(node: &Mutex<INode>) -> Result<MutexGuard<'_, INode>, FileSystemError> {
+    node.lock().map_err(|_| FileSystemError::Poisoned)
+}
+
+// Lock a node where there is no way to report an error. A poisoned node is used as
+// it stands; a panicking writer leaves it partly updated but never invalid.
+fn lock_node_or_recover(node: &Mutex<INode>) -> MutexGuard<'_, INode> {
+    node.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[allow(dead_code)]
+impl INode {
+    // Account for a directory entry naming this node going away. The data itself is
+    // released once the last entry and the last open descriptor are gone.
+    fn drop_link(&mut self) {
+        if let INode::File { links, .. } = self {
+            *links = links.saturating_sub(1);
+        }
+    }
+
+    fn times(&self) -> &Timestamps {
+        match self {
+            INode::Folder { times, .. }
+            | INode::File { times, .. }
+            | INode::Symlink { times, .. } => times,
+        }
+    }
+
+    fn times_mut(&mut self) -> &mut Timestamps {
+        match self {
+            INode::Folder { times, .. }
+            | INode::File { times, .. }
+            | INode::Symlink { times, .. } => times,
+        }
+    }
+
+    fn xattrs(&self) -> &HashMap<String, Vec<u8>> {
+        match self {
+            INode::Folder { xattrs, .. }
+            | INode::File { xattrs, .. }
+            | INode::Symlink { xattrs, .. } => xattrs,
+        }
+    }
+
+    fn xattrs_mut(&mut self) -> &mut HashMap<String, Vec<u8>> {
+        match self {
+            INode::Folder { xattrs, .. }
+            | INode::File { xattrs, .. }
+            | INode::Symlink { xattrs, .. } => xattrs,
+        }
+    }
+
+    // Length of a file as seen through a descriptor, however it is stored
+    fn file_len(&self) -> usize {
+        match self {
+            #[cfg(feature = "compression")]
+            INode::File {
+                packed: Some(packed),
+                ..
+            } => packed.len,
+            INode::File { data, .. } => data.len(),
+            _ => 0,
+        }
+    }
+
+    // A file's bytes, inflated first if the file is stored compressed
+    fn file_contents(&self) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            #[cfg(feature = "compression")]
+            INode::File {
+                packed: Some(packed),
+                ..
+            } => Ok(Cow::Owned(packed.unpack()?)),
+            INode::File { data, .. } => Ok(Cow::Borrowed(&data[..])),
+            _ => Ok(Cow::Borrowed(&[])),
+        }
+    }
+
+    // Switch a compressed file back to holding its plain bytes
+    #[cfg(feature = "compression")]
+    fn inflate(&mut self) -> io::Result<()> {
+        if let INode::File { data, packed, .. } = self {
+            if let Some(stored) = packed.take() {
+                *data = Arc::new(stored.unpack()?);
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        let (is_dir, size, permissions, links, ino) = match self {
+            INode::Folder {
+                contents,
+                permissions,
+                ino,
+                ..
+            } => (true, contents.len(), permissions, 1, *ino),
+            INode::File {
+                permissions,
+                links,
+                ino,
+                ..
+            } => (false, self.file_len(), permissions, *links, *ino),
+            INode::Symlink {
+                target,
+                permissions,
+                ino,
+                ..
+            } => (false, target.len(), permissions, 1, *ino),
+        };
+        let times = self.times();
+        Metadata {
+            is_dir,
+            size,
+            permissions: permissions.clone(),
+            links,
+            ino,
+            created: times.created,
+            modified: times.modified,
+            accessed: times.accessed,
+        }
+    }
+}
+
+// Summary of a node; size is the byte length of a file or the entry count of a folder
+#[derive(Debug, Clone, PartialEq)]
+struct Metadata {
+    is_dir: bool,
+    size: usize,
+    permissions: Permissions,
+    links: usize,
+    // Shared by every hard link to the same file
+    ino: u64,
+    created: SystemTime,
+    modified: SystemTime,
+    accessed: SystemTime,
+}
+
+// Whole file system totals returned by statfs. Files are counted once however many
+// hard links name them, and the root counts as a directory.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[allow(dead_code)]
+struct FsStats {
+    files: usize,
+    directories: usize,
+    bytes_used: usize,
+    // Room left under the quota, if there is one
+    bytes_free: Option<usize>,
+}
+
+// Entry of a folder, as returned by readdir
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+}
+
+// Result of SimpleFileSystem::diff
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+struct FileDiff {
+    // Offset and the byte in each file, wherever they differ within the shorter length
+    changed: Vec<(usize, u8, u8)>,
+    // Whether one file goes on past the end of the other
+    extra_bytes: bool,
+}
+
+// Broken invariant found by fsck
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+enum FsckProblem {
+    // A folder entry whose name could never have been created through a path
+    InvalidName {
+        folder: String,
+        name: String,
+    },
+    // A folder reachable through more than one entry, which may form a cycle
+    SharedFolder(String),
+    // A file whose link count differs from the entries naming it
+    LinkCount {
+        path: String,
+        links: usize,
+        entries: usize,
     },
+    // Bytes counted against the quota differ from the data actually held
+    ByteCount {
+        counted: usize,
+        held: usize,
+    },
+    // An open descriptor on a file that is linked but not in the tree
+    DanglingDescriptor(usize),
+}
+
+// Change reported to subscribers. Paths are absolute and normalized, and name the
+// file as it was reached: a write through a descriptor reports the path it was
+// opened by, or renamed to since.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+enum FsEvent {
+    Created(String),
+    Written(String),
+    Removed(String),
+    Renamed { from: String, to: String },
 }
 
 // Error handling for file system operations
 #[derive(Debug)]
+#[allow(dead_code)]
 enum FileSystemError {
     InvalidType,
     PermissionDenied,
@@ -29,22 +439,162 @@ enum FileSystemError {
     FileExists,
     DirectoryNotEmpty,
     InvalidFileDescriptor,
+    InvalidSeek,
+    InvalidUtf8,
+    TooManySymlinks,
+    WouldBlock,
+    TooManyOpenFiles,
+    ReadOnlyFileSystem,
+    Poisoned,
+    QuotaExceeded,
+    InvalidName,
+    XattrNotFound,
+    InvalidMove,
+    VersionNotFound,
+    FileTooLarge,
+    CrossDevice,
+    Serialization(String),
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for FileSystemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            FileSystemError::InvalidType => "wrong node type for this operation",
+            FileSystemError::PermissionDenied => "permission denied",
+            FileSystemError::FileNotFound => "no such file or directory",
+            FileSystemError::FileExists => "file exists",
+            FileSystemError::DirectoryNotEmpty => "directory not empty",
+            FileSystemError::InvalidFileDescriptor => "bad file descriptor",
+            FileSystemError::InvalidSeek => "invalid seek",
+            FileSystemError::InvalidUtf8 => "file contents are not valid UTF-8",
+            FileSystemError::TooManySymlinks => "too many levels of symbolic links",
+            FileSystemError::WouldBlock => "file is locked",
+            FileSystemError::TooManyOpenFiles => "too many open files",
+            FileSystemError::ReadOnlyFileSystem => "read-only file system",
+            FileSystemError::Poisoned => "a thread panicked while holding a lock",
+            FileSystemError::QuotaExceeded => "file system quota exceeded",
+            FileSystemError::InvalidName => "invalid file name",
+            FileSystemError::XattrNotFound => "no such extended attribute",
+            FileSystemError::InvalidMove => "cannot move a folder inside itself",
+            FileSystemError::VersionNotFound => "no such earlier version",
+            FileSystemError::FileTooLarge => "file too large",
+            FileSystemError::CrossDevice => "cannot move across mount points",
+            FileSystemError::Serialization(reason) => {
+                return write!(f, "could not serialize file system: {}", reason)
+            }
+            FileSystemError::Io(kind) => return write!(f, "host I/O error: {}", kind),
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for FileSystemError {}
+
+impl From<FileSystemError> for io::Error {
+    fn from(err: FileSystemError) -> io::Error {
+        let kind = match err {
+            FileSystemError::InvalidType => io::ErrorKind::InvalidInput,
+            FileSystemError::PermissionDenied => io::ErrorKind::PermissionDenied,
+            FileSystemError::FileNotFound => io::ErrorKind::NotFound,
+            FileSystemError::FileExists => io::ErrorKind::AlreadyExists,
+            FileSystemError::DirectoryNotEmpty => io::ErrorKind::DirectoryNotEmpty,
+            FileSystemError::InvalidFileDescriptor => io::ErrorKind::InvalidInput,
+            FileSystemError::InvalidSeek => io::ErrorKind::InvalidInput,
+            FileSystemError::InvalidUtf8 => io::ErrorKind::InvalidData,
+            FileSystemError::TooManySymlinks => io::ErrorKind::InvalidInput,
+            FileSystemError::WouldBlock => io::ErrorKind::WouldBlock,
+            FileSystemError::TooManyOpenFiles => io::ErrorKind::Other,
+            FileSystemError::ReadOnlyFileSystem => io::ErrorKind::ReadOnlyFilesystem,
+            FileSystemError::Poisoned => io::ErrorKind::Other,
+            FileSystemError::QuotaExceeded => io::ErrorKind::QuotaExceeded,
+            FileSystemError::InvalidName => io::ErrorKind::InvalidFilename,
+            FileSystemError::XattrNotFound => io::ErrorKind::NotFound,
+            FileSystemError::InvalidMove => io::ErrorKind::InvalidInput,
+            FileSystemError::VersionNotFound => io::ErrorKind::NotFound,
+            FileSystemError::FileTooLarge => io::ErrorKind::FileTooLarge,
+            FileSystemError::CrossDevice => io::ErrorKind::CrossesDevices,
+            FileSystemError::Serialization(_) => io::ErrorKind::InvalidData,
+            FileSystemError::Io(kind) => kind,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+// Errors from the host file system, as seen by PersistentFileSystem
+impl From<io::Error> for FileSystemError {
+    fn from(err: io::Error) -> FileSystemError {
+        match err.kind() {
+            io::ErrorKind::NotFound => FileSystemError::FileNotFound,
+            io::ErrorKind::PermissionDenied => FileSystemError::PermissionDenied,
+            io::ErrorKind::AlreadyExists => FileSystemError::FileExists,
+            io::ErrorKind::DirectoryNotEmpty => FileSystemError::DirectoryNotEmpty,
+            io::ErrorKind::NotADirectory | io::ErrorKind::IsADirectory => {
+                FileSystemError::InvalidType
+            }
+            io::ErrorKind::FileTooLarge => FileSystemError::FileTooLarge,
+            io::ErrorKind::CrossesDevices => FileSystemError::CrossDevice,
+            kind => FileSystemError::Io(kind),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
 enum OffsetFrom {
     Start(usize),
     Current(isize),
     End(isize),
 }
 
+// Access mode and behavior requested when opening a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OpenFlags(u8);
+
+#[allow(dead_code)]
+impl OpenFlags {
+    const READ: OpenFlags = OpenFlags(1);
+    const WRITE: OpenFlags = OpenFlags(1 << 1);
+    const APPEND: OpenFlags = OpenFlags(1 << 2);
+    const TRUNC: OpenFlags = OpenFlags(1 << 3);
+    const CREATE: OpenFlags = OpenFlags(1 << 4);
+    // With CREATE, fail if the path already exists instead of opening it
+    const EXCL: OpenFlags = OpenFlags(1 << 5);
+
+    fn contains(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
 // File Descriptor Table Entry
 #[derive(Debug)]
 struct FileDescriptor {
     inode: Arc<Mutex<INode>>,
+    // Components of the path the file was opened by, moved along by renames
+    path: Vec<String>,
+    // For a folder, the number of entries readdir has returned so far
     position: usize,
+    flags: OpenFlags,
+    // Lock this descriptor holds on its file, if any
+    lock: Option<LockKind>,
 }
 
+// Another file system attached below a folder by mount_at. Mounted file systems are
+// Send and Sync so the one holding them still is.
+struct Mount {
+    at: Vec<String>,
+    fs: Box<dyn FileSystem + Send + Sync>,
+}
+
+#[allow(dead_code)]
 trait FileSystem {
     fn create(
         &mut self,
@@ -52,256 +602,7543 @@ trait FileSystem {
         permissions_mode: Permissions,
     ) -> Result<usize, FileSystemError>;
 
+    fn mkdir(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError>;
+
+    fn unlink(&mut self, path: &str) -> Result<(), FileSystemError>;
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FileSystemError>;
+
+    fn list_dir(&self, path: &str) -> Result<Vec<String>, FileSystemError>;
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError>;
+
+    fn stat(&self, path: &str) -> Result<Metadata, FileSystemError>;
+
+    // Whether anything at all is at `path`, a dangling symlink included
+    fn exists(&self, path: &str) -> bool;
+
+    // Checks on what `path` names after following symlinks, false when there is nothing
+    fn is_file(&self, path: &str) -> bool {
+        self.stat(path).is_ok_and(|metadata| !metadata.is_dir)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        self.stat(path).is_ok_and(|metadata| metadata.is_dir)
+    }
+
+    fn chmod(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError>;
+
     fn open(&mut self, path: &str) -> Result<usize, FileSystemError>;
 
+    fn open_with(&mut self, path: &str, flags: OpenFlags) -> Result<usize, FileSystemError>;
+
     fn close(&mut self, fd: usize) -> Result<(), FileSystemError>;
 
     fn write(&mut self, fd: usize, data: &[u8]) -> Result<(), FileSystemError>;
-    fn read(&self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError>;
+    fn read(&mut self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError>;
     fn seek(&mut self, fd: usize, offset: OffsetFrom) -> Result<usize, FileSystemError>;
+    // The descriptor's position, the same as seek(fd, Current(0)) reports
+    fn tell(&self, fd: usize) -> Result<usize, FileSystemError>;
+    fn ftruncate(&mut self, fd: usize, len: usize) -> Result<(), FileSystemError>;
+    fn fstat(&self, fd: usize) -> Result<Metadata, FileSystemError>;
+
+    // Push anything buffered for one descriptor, or for the whole file system, down to
+    // its storage
+    fn flush(&mut self, fd: usize) -> Result<(), FileSystemError>;
+    fn sync_all(&mut self) -> Result<(), FileSystemError>;
 }
 
-struct SimpleFileSystem {
-    root: INode,
-    file_descriptors: HashMap<usize, FileDescriptor>,
-    next_fd: usize,
+// Descriptor operations for async callers. Waiting for the file system is what
+// yields to the executor; each operation then runs to completion while holding it.
+#[cfg(feature = "async")]
+#[allow(dead_code)]
+trait AsyncFileSystem {
+    async fn open(&self, path: &str) -> Result<usize, FileSystemError>;
+    async fn read(&self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError>;
+    async fn write(&self, fd: usize, data: &[u8]) -> Result<(), FileSystemError>;
+    async fn seek(&self, fd: usize, offset: OffsetFrom) -> Result<usize, FileSystemError>;
+    async fn close(&self, fd: usize) -> Result<(), FileSystemError>;
 }
 
-impl SimpleFileSystem {
-    fn new() -> Self {
-        let root = INode::Folder {
-            contents: HashMap::new(),
-            permissions: Permissions::ReadWrite,
-        };
+#[cfg(feature = "async")]
+impl<F: FileSystem> AsyncFileSystem for tokio::sync::Mutex<F> {
+    async fn open(&self, path: &str) -> Result<usize, FileSystemError> {
+        self.lock().await.open(path)
+    }
 
-        SimpleFileSystem {
-            root,
-            file_descriptors: HashMap::new(),
-            next_fd: 1, // Start file descriptors from 1
-        }
+    async fn read(&self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        self.lock().await.read(fd, buffer)
     }
 
-    fn get_file_descriptor(&self, fd: usize) -> Result<Arc<Mutex<INode>>, FileSystemError> {
-        self.file_descriptors
-            .get(&fd)
-            .map(|desc| desc.inode.clone())
-            .ok_or(FileSystemError::InvalidFileDescriptor)
+    async fn write(&self, fd: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        self.lock().await.write(fd, data)
     }
 
-    fn allocate_fd(&mut self, inode: Arc<Mutex<INode>>) -> usize {
-        let fd = self.next_fd;
-        self.next_fd += 1;
-        self.file_descriptors.insert(fd, FileDescriptor { inode, position: 0 });
-        fd
+    async fn seek(&self, fd: usize, offset: OffsetFrom) -> Result<usize, FileSystemError> {
+        self.lock().await.seek(fd, offset)
     }
 
-    fn get_inode(&self, path: &str) -> Result<Arc<Mutex<INode>>, FileSystemError> {
-        let components: Vec<&str> = path.trim_start_matches('/').split('/').collect();
-        let mut current = &self.root;
-        for component in components.iter() {
-            let component_str = component.to_string();
-            if let INode::Folder { contents, .. } = current {
-                match contents.get(&component_str) {
-                    Some(node) => current = node,
-                    None => return Err(FileSystemError::FileNotFound),
-                }
-            } else {
-                return Err(FileSystemError::InvalidType);
+    async fn close(&self, fd: usize) -> Result<(), FileSystemError> {
+        self.lock().await.close(fd)
+    }
+}
+
+// Inode number for the next node created
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+fn next_ino() -> u64 {
+    NEXT_INO.fetch_add(1, Ordering::Relaxed)
+}
+
+// Stamp for the next change that can move folders away from their paths. Shared by
+// every file system, so a DirHandle is never mistaken as current in another one.
+static NEXT_LAYOUT: AtomicU64 = AtomicU64::new(1);
+
+fn next_layout() -> u64 {
+    NEXT_LAYOUT.fetch_add(1, Ordering::Relaxed)
+}
+
+// Longest chain of symlinks followed while resolving a single path
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+// Default limit on the length of a single name, in bytes
+const MAX_NAME_LEN: usize = 255;
+
+// Absolute path string for normalized components
+fn path_of(components: &[String]) -> String {
+    format!("/{}", components.join("/"))
+}
+
+// Append the components of `path` to `base`. Repeated and trailing slashes are collapsed,
+// so "/" and "//" both name the root folder, which has no components. "." is dropped and
+// ".." removes the previous component, stopping at the root.
+fn join_path(mut base: Vec<String>, path: &str) -> Vec<String> {
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                base.pop();
             }
+            name => base.push(name.to_string()),
         }
+    }
+    base
+}
 
-        match current {
-            INode::File { .. } => Ok(Arc::new(Mutex::new(current.clone()))),
-            _ => Err(FileSystemError::InvalidType),
+// Where SimpleFileSystem keeps its nodes. Paths arrive split into components relative
+// to the root. Descriptors hold on to the returned handles, so a backend has to hand
+// out the same handle for a node for as long as it stays reachable.
+#[allow(dead_code)]
+trait StorageBackend {
+    // Find the node at the end of the path, following symlinks on the way. A symlink
+    // at the very end is only followed with `follow_last`.
+    fn resolve(
+        &self,
+        components: &[String],
+        follow_last: bool,
+    ) -> Result<Arc<Mutex<INode>>, FileSystemError>;
+
+    // Add an entry naming `inode` to the parent folder, which must already exist
+    fn insert(
+        &mut self,
+        components: &[String],
+        inode: Arc<Mutex<INode>>,
+    ) -> Result<(), FileSystemError>;
+
+    // Detach an entry from its parent folder once `check` accepts the node
+    fn remove(
+        &mut self,
+        components: &[String],
+        check: &dyn Fn(&INode) -> Result<(), FileSystemError>,
+    ) -> Result<Arc<Mutex<INode>>, FileSystemError>;
+
+    fn list(&self, components: &[String]) -> Result<Vec<String>, FileSystemError>;
+
+    // The name an entry is stored under, which differs from the last component asked
+    // for when names are matched regardless of case
+    fn entry_name(&self, components: &[String]) -> Result<String, FileSystemError>;
+
+    // The key of the entry that `name` refers to in a folder's contents
+    fn entry_key<'a>(
+        &self,
+        contents: &'a HashMap<String, Arc<Mutex<INode>>>,
+        name: &str,
+    ) -> Option<&'a String>;
+
+    fn set_case_insensitive(&mut self, case_insensitive: bool);
+
+    // The root folder, and swapping in a whole new tree at once for restore, format
+    // and loading
+    fn root(&self) -> Arc<Mutex<INode>>;
+    fn set_root(&mut self, root: Arc<Mutex<INode>>);
+
+    // A backend set up like this one, holding the tree under `root`
+    fn with_root(&self, root: Arc<Mutex<INode>>) -> Self
+    where
+        Self: Sized;
+}
+
+// The default backend: a tree of folders held in memory
+struct MemoryBackend {
+    root: Arc<Mutex<INode>>,
+    // Match names regardless of case, while keeping the casing they were created with
+    case_insensitive: bool,
+}
+
+impl MemoryBackend {
+    fn new(now: SystemTime) -> Self {
+        MemoryBackend {
+            root: Arc::new(Mutex::new(INode::Folder {
+                contents: HashMap::new(),
+                permissions: Permissions::ReadWrite,
+                times: Timestamps::new(now),
+                ino: next_ino(),
+                xattrs: HashMap::new(),
+            })),
+            case_insensitive: false,
         }
     }
 }
 
-impl FileSystem for SimpleFileSystem {
-    fn create(
-        &mut self,
-        path: &str,
-        permissions_mode: Permissions,
-    ) -> Result<usize, FileSystemError> {
-        let components: Vec<&str> = path.trim_start_matches('/').split('/').collect();
-        let mut current = &mut self.root;
-
-        for (i, component) in components.iter().enumerate() {
-            let component_str = component.to_string();
-            if let INode::Folder { contents, .. } = current {
-                if i == components.len() - 1 {
-                    if contents.contains_key(&component_str) {
-                        return Err(FileSystemError::FileExists);
+impl StorageBackend for MemoryBackend {
+    fn resolve(
+        &self,
+        components: &[String],
+        follow_last: bool,
+    ) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        let mut pending = components.to_vec();
+        let mut hops = 0;
+        loop {
+            let mut current = self.root.clone();
+            let mut redirect = None;
+            for (i, component) in pending.iter().enumerate() {
+                let next = match &*lock_node(&current)? {
+                    INode::Folder { contents, .. } => self
+                        .entry_key(contents, component)
+                        .map(|key| contents[key].clone())
+                        .ok_or(FileSystemError::FileNotFound)?,
+                    _ => return Err(FileSystemError::InvalidType),
+                };
+
+                let is_last = i == pending.len() - 1;
+                if let INode::Symlink { target, .. } = &*lock_node(&next)? {
+                    if !is_last || follow_last {
+                        // Relative targets are resolved from the folder holding the link
+                        let base = if target.starts_with('/') {
+                            Vec::new()
+                        } else {
+                            pending[..i].to_vec()
+                        };
+                        let mut resolved = join_path(base, target);
+                        resolved.extend_from_slice(&pending[i + 1..]);
+                        redirect = Some(resolved);
                     }
-                    contents.insert(
-                        component_str.clone(),
-                        INode::File {
-                            data: Vec::new(),
-                            permissions: permissions_mode,
-                        },
-                    );
-                    let inode = Arc::new(Mutex::new(contents.get(&component_str).unwrap().clone()));
-                    return Ok(self.allocate_fd(inode));
-                } else {
-                    match contents.get_mut(&component_str) {
-                        Some(INode::Folder { .. }) => {
-                            current = contents.get_mut(&component_str).unwrap()
-                        }
-                        _ => return Err(FileSystemError::InvalidType),
+                }
+                if redirect.is_some() {
+                    break;
+                }
+                current = next;
+            }
+
+            match redirect {
+                Some(resolved) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_DEPTH {
+                        return Err(FileSystemError::TooManySymlinks);
                     }
+                    pending = resolved;
                 }
-            } else {
-                return Err(FileSystemError::InvalidType);
+                None => return Ok(current),
             }
         }
-
-        Err(FileSystemError::InvalidType)
     }
 
-    fn open(&mut self, path: &str) -> Result<usize, FileSystemError> {
-        let inode = self.get_inode(path)?;
-        Ok(self.allocate_fd(inode))
-    }
+    fn insert(
+        &mut self,
+        components: &[String],
+        inode: Arc<Mutex<INode>>,
+    ) -> Result<(), FileSystemError> {
+        let (name, parents) = components.split_last().ok_or(FileSystemError::FileExists)?;
+        let parent = self.resolve(parents, true)?;
 
-    fn close(&mut self, fd: usize) -> Result<(), FileSystemError> {
-        if self.file_descriptors.remove(&fd).is_some() {
-            Ok(())
-        } else {
-            Err(FileSystemError::InvalidFileDescriptor)
+        let mut parent = lock_node(&parent)?;
+        match &mut *parent {
+            INode::Folder { contents, .. } => {
+                if self.entry_key(contents, name).is_some() {
+                    return Err(FileSystemError::FileExists);
+                }
+                contents.insert(name.to_string(), inode);
+                Ok(())
+            }
+            INode::File { .. } | INode::Symlink { .. } => Err(FileSystemError::InvalidType),
         }
     }
 
-    fn write(&mut self, fd: usize, data: &[u8]) -> Result<(), FileSystemError> {
-        let mut file_desc = self.file_descriptors
-            .get_mut(&fd)
-            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+    fn remove(
+        &mut self,
+        components: &[String],
+        check: &dyn Fn(&INode) -> Result<(), FileSystemError>,
+    ) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        let (name, parents) = components
+            .split_last()
+            .ok_or(FileSystemError::InvalidType)?;
+        let parent = self.resolve(parents, true)?;
 
-        let mut inode = file_desc.inode.lock().unwrap();
-        if let INode::File { data: file_data, .. } = &mut *inode {
-            file_data.extend_from_slice(data);
-            Ok(())
-        } else {
-            Err(FileSystemError::InvalidType)
+        let mut parent = lock_node(&parent)?;
+        match &mut *parent {
+            INode::Folder { contents, .. } => {
+                let key = self
+                    .entry_key(contents, name)
+                    .cloned()
+                    .ok_or(FileSystemError::FileNotFound)?;
+                check(&*lock_node(&contents[&key])?)?;
+                Ok(contents.remove(&key).unwrap())
+            }
+            INode::File { .. } | INode::Symlink { .. } => Err(FileSystemError::InvalidType),
         }
     }
 
-    fn read(&self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
-        let file_desc = self.file_descriptors
-            .get(&fd)
-            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+    fn list(&self, components: &[String]) -> Result<Vec<String>, FileSystemError> {
+        let folder = self.resolve(components, true)?;
+        let folder = lock_node(&folder)?;
+        match &*folder {
+            INode::Folder { contents, .. } => Ok(contents.keys().cloned().collect()),
+            INode::File { .. } | INode::Symlink { .. } => Err(FileSystemError::InvalidType),
+        }
+    }
 
-        let mut inode = file_desc.inode.lock().unwrap();
-        if let INode::File { data: file_data, .. } = &*inode {
-            let start = file_desc.position;
-            let end = start + buffer.len();
-            let len = end.min(file_data.len()) - start;
-            buffer[..len].copy_from_slice(&file_data[start..start + len]);
-            Ok(len)
-        } else {
-            Err(FileSystemError::InvalidType)
+    fn entry_name(&self, components: &[String]) -> Result<String, FileSystemError> {
+        let (name, parents) = components
+            .split_last()
+            .ok_or(FileSystemError::InvalidType)?;
+        let parent = self.resolve(parents, true)?;
+        let parent = lock_node(&parent)?;
+        match &*parent {
+            INode::Folder { contents, .. } => self
+                .entry_key(contents, name)
+                .cloned()
+                .ok_or(FileSystemError::FileNotFound),
+            INode::File { .. } | INode::Symlink { .. } => Err(FileSystemError::InvalidType),
         }
     }
 
-    fn seek(&mut self, fd: usize, offset: OffsetFrom) -> Result<usize, FileSystemError> {
-        let mut file_desc = self
-            .file_descriptors
-            .get_mut(&fd)
-            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+    fn entry_key<'a>(
+        &self,
+        contents: &'a HashMap<String, Arc<Mutex<INode>>>,
+        name: &str,
+    ) -> Option<&'a String> {
+        if let Some((key, _)) = contents.get_key_value(name) {
+            return Some(key);
+        }
+        if !self.case_insensitive {
+            return None;
+        }
+        let folded = name.to_lowercase();
+        contents.keys().find(|key| key.to_lowercase() == folded)
+    }
 
-        let inode = file_desc.inode.lock().unwrap();
-        let file_size = if let INode::File { data, .. } = &*inode {
-            data.len()
-        } else {
-            return Err(FileSystemError::InvalidType);
-        };
+    fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
 
-        let new_position = match offset {
-            OffsetFrom::Start(pos) => pos,
-            OffsetFrom::Current(offset) => {
-                if let Some(pos) = file_desc.position.checked_add_signed(offset) {
-                    pos
-                } else {
-                    return Err(FileSystemError::InvalidType);
+    fn root(&self) -> Arc<Mutex<INode>> {
+        self.root.clone()
+    }
+
+    fn set_root(&mut self, root: Arc<Mutex<INode>>) {
+        self.root = root;
+    }
+
+    fn with_root(&self, root: Arc<Mutex<INode>>) -> Self {
+        MemoryBackend {
+            root,
+            case_insensitive: self.case_insensitive,
+        }
+    }
+}
+
+// File system over a storage backend. The descriptor table, positions and permission
+// checks live here, so every backend shares them.
+#[allow(dead_code)]
+struct SimpleFileSystem<B: StorageBackend = MemoryBackend> {
+    backend: B,
+    file_descriptors: HashMap<usize, FileDescriptor>,
+    next_fd: usize,
+    // Where next_fd starts, kept so format can start over from it
+    first_fd: usize,
+    // Numbers of closed descriptors, handed out again lowest first before next_fd
+    // grows, as POSIX does
+    free_fds: BinaryHeap<Reverse<usize>>,
+    max_open_fds: Option<usize>,
+    cwd: Vec<String>,
+    clock: Arc<dyn Clock>,
+    // Bytes of file data currently held, counted as writes and truncations happen.
+    // Atomic so write_at can run from several threads at once.
+    total_bytes: AtomicUsize,
+    max_total_bytes: Option<usize>,
+    // Largest size any one file may grow to
+    max_file_bytes: Option<usize>,
+    max_name_len: usize,
+    // Refuse every operation that would change the tree or file contents
+    read_only: bool,
+    // Suffix tried next by mktemp
+    next_temp: usize,
+    // Earlier versions kept per file; 0 keeps none
+    history_depth: usize,
+    // Folders made on the way to the one create_dir_all was asked for take the
+    // permissions of their parent instead of the requested ones
+    inherit_permissions: bool,
+    // Senders for every receiver handed out by subscribe. Locked so write_at can emit
+    // events from several threads.
+    subscribers: Mutex<Vec<Sender<FsEvent>>>,
+    mounts: Vec<Mount>,
+    // Renewed whenever an entry is removed or the tree is replaced, which is what can
+    // leave a folder cached in a DirHandle away from its path
+    layout: u64,
+    // Descriptors opened inside a mount: the mount's index and the descriptor's number
+    // in that file system
+    mounted_fds: HashMap<usize, (usize, usize)>,
+    // When deduplicating, every distinct file content seen, keyed by its hash
+    #[cfg(feature = "dedup")]
+    shared_data: Option<Mutex<HashMap<blake3::Hash, Arc<Vec<u8>>>>>,
+    // Deflate each file's contents once its last descriptor closes
+    #[cfg(feature = "compression")]
+    compress: bool,
+}
+
+#[allow(dead_code)]
+impl SimpleFileSystem {
+    fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+
+    fn with_clock(clock: impl Clock + 'static) -> Self {
+        let backend = MemoryBackend::new(clock.now());
+        SimpleFileSystem::with_backend(backend, clock)
+    }
+
+    // Limit the total bytes of file data held across all files
+    fn new_with_quota(max_total_bytes: usize) -> Self {
+        let mut fs = Self::new();
+        fs.max_total_bytes = Some(max_total_bytes);
+        fs
+    }
+
+    fn builder() -> SimpleFileSystemBuilder {
+        SimpleFileSystemBuilder {
+            root_permissions: Permissions::ReadWrite,
+            max_total_bytes: None,
+            max_file_bytes: None,
+            case_insensitive: false,
+            max_name_len: MAX_NAME_LEN,
+            starting_fd: 1,
+            max_open_fds: None,
+            read_only: false,
+            history_depth: 0,
+            inherit_permissions: false,
+            #[cfg(feature = "dedup")]
+            dedup: false,
+            #[cfg(feature = "compression")]
+            compress: false,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<B: StorageBackend> SimpleFileSystem<B> {
+    // Copy the whole tree so it can be put back later with restore. Open descriptors
+    // are not part of the snapshot.
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            root: deep_clone(&self.backend.root(), &mut HashMap::new()),
+        }
+    }
+
+    // Replace the tree with a snapshot. Every open descriptor is closed, since the files
+    // they refer to are no longer part of the tree.
+    fn restore(&mut self, snapshot: Snapshot) {
+        let closed = self.file_descriptors.drain().map(|(fd, _)| Reverse(fd));
+        self.free_fds.extend(closed);
+        self.total_bytes = AtomicUsize::new(usage_of(&snapshot.root, &mut HashSet::new()));
+        self.backend.set_root(snapshot.root);
+        self.layout = next_layout();
+        let cwd_is_folder = self
+            .lookup(&self.cwd)
+            .is_ok_and(|node| matches!(&*lock_node_or_recover(&node), INode::Folder { .. }));
+        if !cwd_is_folder {
+            self.cwd.clear();
+        }
+    }
+
+    // Empty the tree, close every descriptor and drop every mount, leaving the file
+    // system as it was when built. Settings such as the quota, case sensitivity and the root folder's
+    // permissions are kept, and so are subscribers, who see each top-level entry removed.
+    fn format(&mut self) {
+        let mut removed = self.backend.list(&[]).unwrap_or_default();
+        removed.sort();
+        let permissions = lock_node_or_recover(&self.backend.root())
+            .metadata()
+            .permissions;
+        self.backend.set_root(Arc::new(Mutex::new(INode::Folder {
+            contents: HashMap::new(),
+            permissions,
+            times: Timestamps::new(self.clock.now()),
+            ino: next_ino(),
+            xattrs: HashMap::new(),
+        })));
+        self.layout = next_layout();
+        self.file_descriptors.clear();
+        // Their mount points are gone along with the rest of the tree
+        self.mounts.clear();
+        self.mounted_fds.clear();
+        self.free_fds.clear();
+        self.next_fd = self.first_fd;
+        self.cwd.clear();
+        self.total_bytes = AtomicUsize::new(0);
+        self.next_temp = 0;
+        #[cfg(feature = "dedup")]
+        if let Some(shared) = &self.shared_data {
+            shared
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+        for name in removed {
+            self.emit(FsEvent::Removed(path_of(&[name])));
+        }
+    }
+
+    // Resolve a folder once for create_in and open_in. Like the other methods outside
+    // FileSystem, this only sees this tree, not mounts.
+    fn open_dir(&self, path: &str) -> Result<DirHandle, FileSystemError> {
+        let path = self.split_path(path);
+        let folder = self.lookup(&path)?;
+        if !matches!(&*lock_node(&folder)?, INode::Folder { .. }) {
+            return Err(FileSystemError::InvalidType);
+        }
+        Ok(DirHandle {
+            path,
+            folder,
+            layout: self.layout,
+        })
+    }
+
+    // The folder a handle names, resolved again only when the cached one may be stale
+    fn dir_folder(&self, dir: &DirHandle) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        if dir.layout == self.layout {
+            return Ok(dir.folder.clone());
+        }
+        let folder = self.lookup(&dir.path)?;
+        if !matches!(&*lock_node(&folder)?, INode::Folder { .. }) {
+            return Err(FileSystemError::InvalidType);
+        }
+        Ok(folder)
+    }
+
+    // Components of the entry `name` in the handle's folder. Only a single name is
+    // accepted; anything else goes through the path-based methods.
+    fn dir_entry_path(&self, dir: &DirHandle, name: &str) -> Result<Vec<String>, FileSystemError> {
+        if name == "." || name == ".." {
+            return Err(FileSystemError::InvalidName);
+        }
+        let mut path = dir.path.clone();
+        path.push(name.to_string());
+        self.validate_name(&path)?;
+        Ok(path)
+    }
+
+    // create for a file named `name` in the handle's folder. The file is added the way
+    // create adds one, with the same checks and events, so the cached folder is not used.
+    fn create_in(
+        &mut self,
+        dir: &DirHandle,
+        name: &str,
+        permissions: Permissions,
+    ) -> Result<usize, FileSystemError> {
+        self.check_fd_limit()?;
+        let path = self.dir_entry_path(dir, name)?;
+        let inode = self.insert_node(&path_of(&path), self.new_file_node(permissions))?;
+        Ok(self.allocate_fd(inode, path, OpenFlags::READ | OpenFlags::WRITE))
+    }
+
+    // open for the entry `name` in the handle's folder. A symlink there is followed
+    // through the path-based open.
+    fn open_in(&mut self, dir: &DirHandle, name: &str) -> Result<usize, FileSystemError> {
+        self.check_fd_limit()?;
+        let path = self.dir_entry_path(dir, name)?;
+        let folder = self.dir_folder(dir)?;
+        let inode = match &*lock_node(&folder)? {
+            INode::Folder { contents, .. } => self
+                .backend
+                .entry_key(contents, name)
+                .map(|key| contents[key].clone())
+                .ok_or(FileSystemError::FileNotFound)?,
+            _ => return Err(FileSystemError::InvalidType),
+        };
+        if matches!(&*lock_node(&inode)?, INode::Symlink { .. }) {
+            return self.open(&path_of(&path));
+        }
+        #[cfg(feature = "compression")]
+        lock_node(&inode)?.inflate()?;
+        Ok(self.allocate_fd(inode, path, OpenFlags::READ | OpenFlags::WRITE))
+    }
+
+    // Create every file in `entries` with its contents and permissions, making parent
+    // folders as needed. Either all of them are created or, if one fails, the files and
+    // folders made so far are removed again. Nothing that was there before is touched,
+    // open descriptors included.
+    fn populate(&mut self, entries: &[(&str, &[u8], Permissions)]) -> Result<(), FileSystemError> {
+        let mut made = Vec::new();
+        let result = entries.iter().try_for_each(|(path, data, permissions)| {
+            let components = self.split_path(path);
+            for depth in 1..=components.len() {
+                let path = path_of(&components[..depth]);
+                if !self.exists(&path) {
+                    made.push(path);
                 }
             }
-            OffsetFrom::End(offset) => {
-                if let Some(pos) = file_size.checked_add_signed(offset) {
-                    pos
-                } else {
-                    return Err(FileSystemError::InvalidType);
+            self.populate_entry(path, data, permissions)
+        });
+        if result.is_err() {
+            // Newest first, so each folder is empty again by the time it goes
+            for path in made.iter().rev() {
+                if self.is_dir(path) {
+                    let _ = self.rmdir(path);
+                } else if self.exists(path) {
+                    let _ = self.unlink(path);
                 }
             }
-        };
+        }
+        result
+    }
 
-        file_desc.position = new_position.min(file_size);
-        Ok(file_desc.position)
+    fn populate_entry(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        permissions: &Permissions,
+    ) -> Result<(), FileSystemError> {
+        let components = self.split_path(path);
+        if let Some((_, parent)) = components.split_last() {
+            // Folders made here only exist to hold the file, so all of them count as
+            // intermediate and take their parent's permissions when inheriting is on
+            self.create_folders(parent, &Permissions::ReadWrite, parent.len())?;
+        }
+        self.write_new_file(path, data, permissions.clone())
     }
 }
 
-// Function to mount the file system
-pub fn mount() -> Box<dyn FileSystem> {
-    Box::new(SimpleFileSystem::new())
+// An independent copy of the tree and settings. Descriptors are not copied, their
+// numbers are free again in the copy, and nobody is subscribed to its events yet.
+// Mounted file systems cannot be copied, so the copy has none.
+impl<B: StorageBackend> Clone for SimpleFileSystem<B> {
+    fn clone(&self) -> Self {
+        let root = deep_clone(&self.backend.root(), &mut HashMap::new());
+        let mut free_fds = self.free_fds.clone();
+        free_fds.extend(self.file_descriptors.keys().map(|&fd| Reverse(fd)));
+        free_fds.extend(self.mounted_fds.keys().map(|&fd| Reverse(fd)));
+        SimpleFileSystem {
+            total_bytes: AtomicUsize::new(usage_of(&root, &mut HashSet::new())),
+            backend: self.backend.with_root(root),
+            file_descriptors: HashMap::new(),
+            next_fd: self.next_fd,
+            first_fd: self.first_fd,
+            free_fds,
+            max_open_fds: self.max_open_fds,
+            cwd: self.cwd.clone(),
+            clock: self.clock.clone(),
+            max_total_bytes: self.max_total_bytes,
+            max_file_bytes: self.max_file_bytes,
+            max_name_len: self.max_name_len,
+            read_only: self.read_only,
+            next_temp: self.next_temp,
+            history_depth: self.history_depth,
+            inherit_permissions: self.inherit_permissions,
+            subscribers: Mutex::new(Vec::new()),
+            mounts: Vec::new(),
+            layout: next_layout(),
+            mounted_fds: HashMap::new(),
+            #[cfg(feature = "dedup")]
+            shared_data: self.shared_data.as_ref().map(|shared| {
+                Mutex::new(
+                    shared
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .clone(),
+                )
+            }),
+            #[cfg(feature = "compression")]
+            compress: self.compress,
+        }
+    }
 }
 
-fn main() {
-    let mut fs = mount();
-    println!("File system mounted successfully!");
+// Copy of a file system tree taken by SimpleFileSystem::snapshot
+#[allow(dead_code)]
+struct Snapshot {
+    root: Arc<Mutex<INode>>,
+}
 
-    // Test creating a file
-    match fs.create("/new_file.txt", Permissions::ReadWrite) {
-        Ok(fd) => {
-            println!("File created successfully with file descriptor: {}", fd);
+// Folder opened by open_dir. It names the folder by path, but keeps the resolved node
+// so files can be opened in it without walking from the root again until something is
+// removed or renamed.
+#[allow(dead_code)]
+struct DirHandle {
+    path: Vec<String>,
+    folder: Arc<Mutex<INode>>,
+    // The file system's layout stamp when the folder was resolved
+    layout: u64,
+}
 
-            // Test writing to the file
-            match fs.write(fd, b"Hello, world!") {
-                Ok(()) => println!("Data written successfully."),
-                Err(e) => println!("Error writing data: {:?}", e),
+// Copy a node and everything beneath it. Files reached through several hard links are
+// copied once, so the copies stay linked to each other; locks are not carried over,
+// and every copy gets an inode number of its own.
+fn deep_clone(
+    node: &Arc<Mutex<INode>>,
+    copies: &mut HashMap<*const Mutex<INode>, Arc<Mutex<INode>>>,
+) -> Arc<Mutex<INode>> {
+    if let Some(copy) = copies.get(&Arc::as_ptr(node)) {
+        return copy.clone();
+    }
+    let copy = match &*lock_node_or_recover(node) {
+        INode::Folder {
+            contents,
+            permissions,
+            times,
+            xattrs,
+            ..
+        } => INode::Folder {
+            contents: contents
+                .iter()
+                .map(|(name, child)| (name.clone(), deep_clone(child, copies)))
+                .collect(),
+            permissions: permissions.clone(),
+            times: *times,
+            ino: next_ino(),
+            xattrs: xattrs.clone(),
+        },
+        INode::File {
+            data,
+            permissions,
+            links,
+            times,
+            xattrs,
+            history,
+            #[cfg(feature = "compression")]
+            packed,
+            ..
+        } => INode::File {
+            data: data.clone(),
+            permissions: permissions.clone(),
+            links: *links,
+            times: *times,
+            ino: next_ino(),
+            xattrs: xattrs.clone(),
+            history: history.clone(),
+            lock: LockState::Unlocked,
+            #[cfg(feature = "compression")]
+            packed: packed.clone(),
+        },
+        INode::Symlink {
+            target,
+            permissions,
+            times,
+            xattrs,
+            ..
+        } => INode::Symlink {
+            target: target.clone(),
+            permissions: permissions.clone(),
+            times: *times,
+            ino: next_ino(),
+            xattrs: xattrs.clone(),
+        },
+    };
+    let copy = Arc::new(Mutex::new(copy));
+    copies.insert(Arc::as_ptr(node), copy.clone());
+    copy
+}
+
+// Gather the files under a folder that remove_dir_all is about to unlink, failing if
+// any folder on the way is read-only. Symlinks are removed, not followed.
+#[allow(dead_code)]
+fn collect_removable_files(
+    node: &Arc<Mutex<INode>>,
+    files: &mut Vec<Arc<Mutex<INode>>>,
+) -> Result<(), FileSystemError> {
+    match &*lock_node(node)? {
+        INode::Folder {
+            contents,
+            permissions,
+            ..
+        } => {
+            if !permissions.can_write() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+            for child in contents.values() {
+                collect_removable_files(child, files)?;
             }
+        }
+        INode::File { .. } => files.push(node.clone()),
+        INode::Symlink { .. } => {}
+    }
+    Ok(())
+}
 
-            // Test seeking to the start of the file
-            match fs.seek(fd, OffsetFrom::Start(0)) {
-                Ok(pos) => println!("Seeked to position: {}", pos),
-                Err(e) => println!("Error seeking file: {:?}", e),
+// Pre-order traversal behind SimpleFileSystem::walk. Symlinks are yielded but not
+// followed.
+#[allow(dead_code)]
+struct Walk {
+    // Nodes still to visit, the next one last
+    pending: Vec<(Vec<String>, Arc<Mutex<INode>>)>,
+}
+
+#[allow(dead_code)]
+impl Walk {
+    fn push_children(&mut self, path: &[String], folder: &Arc<Mutex<INode>>) {
+        if let INode::Folder { contents, .. } = &*lock_node_or_recover(folder) {
+            let mut children: Vec<_> = contents.iter().collect();
+            children.sort_by(|a, b| b.0.cmp(a.0));
+            for (name, child) in children {
+                let mut child_path = path.to_vec();
+                child_path.push(name.clone());
+                self.pending.push((child_path, child.clone()));
             }
+        }
+    }
+}
 
-            // Test reading from the file
-            let mut buffer = [0; 13];
-            match fs.read(fd, &mut buffer) {
-                Ok(bytes_read) => {
-                    println!(
-                        "Read {} bytes: {:?}",
-                        bytes_read,
-                        String::from_utf8_lossy(&buffer)
-                    );
+impl Iterator for Walk {
+    type Item = (String, Metadata);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, node) = self.pending.pop()?;
+        let metadata = lock_node_or_recover(&node).metadata();
+        self.push_children(&path, &node);
+        Some((path_of(&path), metadata))
+    }
+}
+
+// Whether `name` matches a glob `pattern` made of literals, `*` and `?`. On a mismatch
+// only the latest `*` is retried, taking one more character, since an earlier star
+// could never match anything the latest one cannot; this keeps the match from going
+// exponential in the number of stars.
+#[allow(dead_code)]
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position of the latest star and of the name character it was matched up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
                 }
-                Err(e) => println!("Error reading data: {:?}", e),
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn usage_of(node: &Arc<Mutex<INode>>, seen: &mut HashSet<*const Mutex<INode>>) -> usize {
+    match &*lock_node_or_recover(node) {
+        INode::Folder { contents, .. } => {
+            contents.values().map(|child| usage_of(child, seen)).sum()
+        }
+        file @ INode::File { .. } if seen.insert(Arc::as_ptr(node)) => file.file_len(),
+        INode::File { .. } | INode::Symlink { .. } => 0,
+    }
+}
+
+// Rough bookkeeping cost of one node and of one entry naming it in a folder, on top of
+// the bytes they hold
+#[allow(dead_code)]
+const NODE_OVERHEAD: usize = size_of::<Mutex<INode>>() + 2 * size_of::<usize>();
+#[allow(dead_code)]
+const ENTRY_OVERHEAD: usize = size_of::<(String, Arc<Mutex<INode>>)>();
+
+// Estimated bytes held by a node and everything beneath it, for memory_usage. Hard
+// linked files are counted once, and file buffers by their capacity, since that is
+// what stays allocated after a truncation until compact gives it back.
+#[allow(dead_code)]
+fn footprint(node: &Arc<Mutex<INode>>, seen: &mut HashSet<*const Mutex<INode>>) -> usize {
+    if !seen.insert(Arc::as_ptr(node)) {
+        return 0;
+    }
+    let node = lock_node_or_recover(node);
+    let xattrs: usize = node
+        .xattrs()
+        .iter()
+        .map(|(name, value)| ENTRY_OVERHEAD + name.len() + value.len())
+        .sum();
+    let held = match &*node {
+        INode::Folder { contents, .. } => contents
+            .iter()
+            .map(|(name, child)| ENTRY_OVERHEAD + name.len() + footprint(child, seen))
+            .sum(),
+        INode::File { data, history, .. } => {
+            let versions: usize = history.iter().map(|version| version.data.capacity()).sum();
+            data.capacity() + versions
+        }
+        INode::Symlink { target, .. } => target.len(),
+    };
+    // Contents at rest are held compressed instead
+    #[cfg(feature = "compression")]
+    let held = match &*node {
+        INode::File {
+            packed: Some(packed),
+            ..
+        } => held + packed.bytes.len(),
+        _ => held,
+    };
+    NODE_OVERHEAD + xattrs + held
+}
+
+// Release the spare capacity of every file buffer under a node for compact, returning
+// the bytes freed. Buffers shared with another file or a version are left alone.
+#[allow(dead_code)]
+fn compact_node(node: &Arc<Mutex<INode>>, seen: &mut HashSet<*const Mutex<INode>>) -> usize {
+    if !seen.insert(Arc::as_ptr(node)) {
+        return 0;
+    }
+    match &mut *lock_node_or_recover(node) {
+        INode::Folder { contents, .. } => contents
+            .values()
+            .map(|child| compact_node(child, seen))
+            .sum(),
+        INode::File { data, history, .. } => std::iter::once(data)
+            .chain(history.iter_mut().map(|version| &mut version.data))
+            .filter_map(Arc::get_mut)
+            .map(|buffer| {
+                let before = buffer.capacity();
+                buffer.shrink_to_fit();
+                before - buffer.capacity()
+            })
+            .sum(),
+        INode::Symlink { .. } => 0,
+    }
+}
+
+// Add a node and everything beneath it to statfs totals
+#[allow(dead_code)]
+fn tally(node: &Arc<Mutex<INode>>, seen: &mut HashSet<*const Mutex<INode>>, stats: &mut FsStats) {
+    match &*lock_node_or_recover(node) {
+        INode::Folder { contents, .. } => {
+            stats.directories += 1;
+            for child in contents.values() {
+                tally(child, seen, stats);
             }
+        }
+        file @ INode::File { .. } if seen.insert(Arc::as_ptr(node)) => {
+            stats.files += 1;
+            stats.bytes_used += file.file_len();
+        }
+        INode::File { .. } | INode::Symlink { .. } => {}
+    }
+}
 
-            // Test seeking to the end of the file
-            match fs.seek(fd, OffsetFrom::End(-6)) {
-                Ok(pos) => println!("Seeked to position: {}", pos),
-                Err(e) => println!("Error seeking file: {:?}", e),
+// Files reached by fsck, with the path each was first seen at and how many entries
+// name it
+#[allow(dead_code)]
+type FoundFiles = HashMap<*const Mutex<INode>, (String, Arc<Mutex<INode>>, usize)>;
+
+// Check the folders under `node` for fsck, gathering the files they hold
+#[allow(dead_code)]
+fn check_tree(
+    node: &Arc<Mutex<INode>>,
+    path: &mut Vec<String>,
+    folders: &mut HashSet<*const Mutex<INode>>,
+    files: &mut FoundFiles,
+    problems: &mut Vec<FsckProblem>,
+) {
+    let entries: Vec<(String, Arc<Mutex<INode>>)> = match &*lock_node_or_recover(node) {
+        INode::Folder { contents, .. } => contents
+            .iter()
+            .map(|(name, child)| (name.clone(), child.clone()))
+            .collect(),
+        _ => return,
+    };
+    if !folders.insert(Arc::as_ptr(node)) {
+        problems.push(FsckProblem::SharedFolder(path_of(path)));
+        return;
+    }
+    for (name, child) in entries {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            problems.push(FsckProblem::InvalidName {
+                folder: path_of(path),
+                name: name.clone(),
+            });
+        }
+        path.push(name);
+        if matches!(&*lock_node_or_recover(&child), INode::File { .. }) {
+            files
+                .entry(Arc::as_ptr(&child))
+                .or_insert_with(|| (path_of(path), child.clone(), 0))
+                .2 += 1;
+        }
+        check_tree(&child, path, folders, files, problems);
+        path.pop();
+    }
+}
+
+// Append the entries of a folder to a print_tree rendering, each line starting with
+// `prefix` to continue the branches of the folders above it
+#[allow(dead_code)]
+fn render_tree(folder: &Arc<Mutex<INode>>, prefix: &str, out: &mut String) {
+    let mut entries: Vec<(String, Arc<Mutex<INode>>)> = match &*lock_node_or_recover(folder) {
+        INode::Folder { contents, .. } => contents
+            .iter()
+            .map(|(name, node)| (name.clone(), node.clone()))
+            .collect(),
+        _ => return,
+    };
+    entries.sort_by_cached_key(|(name, node)| {
+        let is_folder = matches!(&*lock_node_or_recover(node), INode::Folder { .. });
+        (!is_folder, name.clone())
+    });
+
+    for (i, (name, node)) in entries.iter().enumerate() {
+        let is_last = i == entries.len() - 1;
+        let branch = if is_last { "└── " } else { "├── " };
+        let label = match &*lock_node_or_recover(node) {
+            INode::Folder { permissions, .. } => {
+                format!("{}/ ({})", name, permissions_label(permissions))
             }
+            file @ INode::File { permissions, .. } => format!(
+                "{} ({} bytes, {})",
+                name,
+                file.file_len(),
+                permissions_label(permissions)
+            ),
+            INode::Symlink { target, .. } => format!("{} -> {}", name, target),
+        };
+        out.push_str(&format!("{}{}{}\n", prefix, branch, label));
 
-            // Test reading from the new position
-            let mut buffer = [0; 6];
-            match fs.read(fd, &mut buffer) {
-                Ok(bytes_read) => {
-                    println!(
-                        "Read {} bytes: {:?}",
-                        bytes_read,
-                        String::from_utf8_lossy(&buffer)
-                    );
+        let child_prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
+        render_tree(node, &child_prefix, out);
+    }
+}
+
+#[allow(dead_code)]
+fn permissions_label(permissions: &Permissions) -> &'static str {
+    match permissions {
+        Permissions::Read => "r-",
+        Permissions::Write => "-w",
+        Permissions::ReadWrite => "rw",
+    }
+}
+
+// Count the names each file has within a tree, so a clone of the tree does not keep
+// link counts for names outside it
+#[allow(dead_code)]
+fn count_links(
+    node: &Arc<Mutex<INode>>,
+    links: &mut HashMap<*const Mutex<INode>, (Arc<Mutex<INode>>, usize)>,
+) {
+    match &*lock_node_or_recover(node) {
+        INode::Folder { contents, .. } => {
+            for child in contents.values() {
+                count_links(child, links);
+            }
+        }
+        INode::File { .. } => {
+            links
+                .entry(Arc::as_ptr(node))
+                .or_insert((node.clone(), 0))
+                .1 += 1;
+        }
+        INode::Symlink { .. } => {}
+    }
+}
+
+// Set the link count of every file in a tree to the names it has there
+#[allow(dead_code)]
+fn recount_links(root: &Arc<Mutex<INode>>) {
+    let mut links = HashMap::new();
+    count_links(root, &mut links);
+    for (node, count) in links.into_values() {
+        if let INode::File {
+            links: file_links, ..
+        } = &mut *lock_node_or_recover(&node)
+        {
+            *file_links = count;
+        }
+    }
+}
+
+// Whether `target` is anywhere below `folder`
+#[allow(dead_code)]
+fn holds_node(folder: &Arc<Mutex<INode>>, target: &Arc<Mutex<INode>>) -> bool {
+    match &*lock_node_or_recover(folder) {
+        INode::Folder { contents, .. } => contents
+            .values()
+            .any(|child| Arc::ptr_eq(child, target) || holds_node(child, target)),
+        _ => false,
+    }
+}
+
+#[allow(dead_code)]
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// Options for a new in-memory SimpleFileSystem; anything left unset keeps the
+// defaults of SimpleFileSystem::new
+#[allow(dead_code)]
+struct SimpleFileSystemBuilder {
+    root_permissions: Permissions,
+    max_total_bytes: Option<usize>,
+    max_file_bytes: Option<usize>,
+    case_insensitive: bool,
+    max_name_len: usize,
+    starting_fd: usize,
+    max_open_fds: Option<usize>,
+    read_only: bool,
+    history_depth: usize,
+    inherit_permissions: bool,
+    #[cfg(feature = "dedup")]
+    dedup: bool,
+    #[cfg(feature = "compression")]
+    compress: bool,
+}
+
+#[allow(dead_code)]
+impl SimpleFileSystemBuilder {
+    fn root_permissions(mut self, permissions: Permissions) -> Self {
+        self.root_permissions = permissions;
+        self
+    }
+
+    fn max_total_bytes(mut self, max_total_bytes: usize) -> Self {
+        self.max_total_bytes = Some(max_total_bytes);
+        self
+    }
+
+    // Limit the size of each file on its own, whatever room the quota leaves
+    fn max_file_bytes(mut self, max_file_bytes: usize) -> Self {
+        self.max_file_bytes = Some(max_file_bytes);
+        self
+    }
+
+    fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    fn max_name_len(mut self, max_name_len: usize) -> Self {
+        self.max_name_len = max_name_len;
+        self
+    }
+
+    // Number handed out by the first open or create
+    fn starting_fd(mut self, fd: usize) -> Self {
+        self.starting_fd = fd;
+        self
+    }
+
+    // Most descriptors that can be open at once
+    fn max_open_fds(mut self, max_open_fds: usize) -> Self {
+        self.max_open_fds = Some(max_open_fds);
+        self
+    }
+
+    fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // Keep up to `depth` earlier versions of each file for revert
+    fn history_depth(mut self, depth: usize) -> Self {
+        self.history_depth = depth;
+        self
+    }
+
+    // Give intermediate folders made by create_dir_all the permissions of the nearest
+    // folder above them
+    fn inherit_permissions(mut self, inherit: bool) -> Self {
+        self.inherit_permissions = inherit;
+        self
+    }
+
+    // Store identical file contents once, at the cost of hashing the file after each write
+    #[cfg(feature = "dedup")]
+    fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    // Keep file contents compressed while no descriptor has them open. Reads and
+    // writes still see the plain bytes, at the cost of inflating on every open.
+    #[cfg(feature = "compression")]
+    fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    fn build(self) -> SimpleFileSystem {
+        self.build_with_backend(MemoryBackend::new(SystemClock.now()))
+    }
+
+    fn build_with_backend<B: StorageBackend>(self, backend: B) -> SimpleFileSystem<B> {
+        let mut fs = SimpleFileSystem::with_backend(backend, SystemClock);
+        if let INode::Folder { permissions, .. } = &mut *lock_node_or_recover(&fs.backend.root()) {
+            *permissions = self.root_permissions;
+        }
+        fs.backend.set_case_insensitive(self.case_insensitive);
+        fs.max_total_bytes = self.max_total_bytes;
+        fs.max_file_bytes = self.max_file_bytes;
+        fs.max_name_len = self.max_name_len;
+        fs.next_fd = self.starting_fd;
+        fs.first_fd = self.starting_fd;
+        fs.max_open_fds = self.max_open_fds;
+        fs.read_only = self.read_only;
+        fs.history_depth = self.history_depth;
+        fs.inherit_permissions = self.inherit_permissions;
+        #[cfg(feature = "dedup")]
+        if self.dedup {
+            fs.shared_data = Some(Mutex::new(HashMap::new()));
+        }
+        #[cfg(feature = "compression")]
+        {
+            fs.compress = self.compress;
+        }
+        fs
+    }
+}
+
+#[allow(dead_code)]
+impl<B: StorageBackend> SimpleFileSystem<B> {
+    fn with_backend(backend: B, clock: impl Clock + 'static) -> Self {
+        SimpleFileSystem {
+            backend,
+            file_descriptors: HashMap::new(),
+            next_fd: 1, // Start file descriptors from 1
+            first_fd: 1,
+            free_fds: BinaryHeap::new(),
+            max_open_fds: None,
+            cwd: Vec::new(),
+            clock: Arc::new(clock),
+            total_bytes: AtomicUsize::new(0),
+            max_total_bytes: None,
+            max_file_bytes: None,
+            max_name_len: MAX_NAME_LEN,
+            read_only: false,
+            next_temp: 0,
+            history_depth: 0,
+            inherit_permissions: false,
+            subscribers: Mutex::new(Vec::new()),
+            mounts: Vec::new(),
+            layout: next_layout(),
+            mounted_fds: HashMap::new(),
+            #[cfg(feature = "dedup")]
+            shared_data: None,
+            #[cfg(feature = "compression")]
+            compress: false,
+        }
+    }
+
+    // When deduplicating, swap freshly written file data for an identical buffer that
+    // another file already holds, so equal contents are stored once
+    #[cfg_attr(not(feature = "dedup"), allow(unused_variables))]
+    fn share_data(&self, data: &mut Arc<Vec<u8>>) {
+        #[cfg(feature = "dedup")]
+        if let Some(shared) = &self.shared_data {
+            let mut shared = shared.lock().unwrap_or_else(PoisonError::into_inner);
+            let hash = blake3::hash(data);
+            match shared.get(&hash) {
+                Some(existing) => *data = existing.clone(),
+                None => {
+                    // Drop contents no file holds any more before remembering a new one
+                    shared.retain(|_, buffer| Arc::strong_count(buffer) > 1);
+                    shared.insert(hash, data.clone());
                 }
-                Err(e) => println!("Error reading data: {:?}", e),
             }
+        }
+    }
 
-            // Test closing the file
-            match fs.close(fd) {
-                Ok(()) => println!("File closed successfully."),
-                Err(e) => println!("Error closing file: {:?}", e),
+    // When keeping versions, remember a file's contents before they change
+    fn keep_version(
+        &self,
+        history: &mut VecDeque<Version>,
+        data: &Arc<Vec<u8>>,
+        modified: SystemTime,
+    ) {
+        if self.history_depth > 0 {
+            history.push_front(Version {
+                data: data.clone(),
+                modified,
+            });
+            history.truncate(self.history_depth);
+        }
+    }
+
+    // Switch between read-only and read-write in place, like `mount -o remount`. The
+    // tree and open descriptors are untouched.
+    fn remount(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    // Receive an FsEvent for every change made from now on. Dropping the receiver ends
+    // the subscription.
+    fn subscribe(&mut self) -> Receiver<FsEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+        receiver
+    }
+
+    fn emit(&self, event: FsEvent) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn check_writable(&self) -> Result<(), FileSystemError> {
+        if self.read_only {
+            Err(FileSystemError::ReadOnlyFileSystem)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Adding or removing an entry changes the folder holding it, so that folder has to
+    // be writable, as a directory's write bit governs on Unix
+    fn check_parent_writable(&self, components: &[String]) -> Result<(), FileSystemError> {
+        let parent = match components.split_last() {
+            Some((_, parent)) => self.lookup(parent)?,
+            None => return Ok(()),
+        };
+        let parent = lock_node(&parent)?;
+        match &*parent {
+            INode::Folder { permissions, .. } if !permissions.can_write() => {
+                Err(FileSystemError::PermissionDenied)
             }
+            _ => Ok(()),
         }
-        Err(e) => println!("Error creating file: {:?}", e),
+    }
+
+    // Check the name a new entry would be created under
+    fn validate_name(&self, components: &[String]) -> Result<(), FileSystemError> {
+        let name = match components.last() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        if name.is_empty() || name.contains(['/', '\0']) || name.len() > self.max_name_len {
+            return Err(FileSystemError::InvalidName);
+        }
+        Ok(())
+    }
+
+    // Account for `growth` more bytes of file data, failing if that would go over quota
+    fn reserve_bytes(&self, growth: usize) -> Result<(), FileSystemError> {
+        let max = self.max_total_bytes.unwrap_or(usize::MAX);
+        self.total_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+                total.checked_add(growth).filter(|total| *total <= max)
+            })
+            .map(|_| ())
+            .map_err(|_| FileSystemError::QuotaExceeded)
+    }
+
+    // Fail if a file growing to `len` bytes would go over the per-file limit
+    fn check_file_size(&self, len: usize) -> Result<(), FileSystemError> {
+        match self.max_file_bytes {
+            Some(max) if len > max => Err(FileSystemError::FileTooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    fn release_bytes(&self, freed: usize) {
+        self.total_bytes.fetch_sub(freed, Ordering::SeqCst);
+    }
+
+    // A file's data stops counting once no directory entry names it and no descriptor
+    // has it open
+    fn release_if_orphaned(&mut self, inode: &Arc<Mutex<INode>>) {
+        if let file @ INode::File { links: 0, .. } = &*lock_node_or_recover(inode) {
+            if !self.is_open(inode) {
+                self.release_bytes(file.file_len());
+            }
+        }
+    }
+
+    fn is_open(&self, inode: &Arc<Mutex<INode>>) -> bool {
+        self.file_descriptors
+            .values()
+            .any(|desc| Arc::ptr_eq(&desc.inode, inode))
+    }
+
+    fn get_file_descriptor(&self, fd: usize) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        self.check_own_fd(fd)?;
+        self.file_descriptors
+            .get(&fd)
+            .map(|desc| desc.inode.clone())
+            .ok_or(FileSystemError::InvalidFileDescriptor)
+    }
+
+    // Fail for a descriptor opened inside a mount. What it refers to is only reachable
+    // through the mounted file system's FileSystem methods, not as a node of this tree.
+    fn check_own_fd(&self, fd: usize) -> Result<(), FileSystemError> {
+        if self.mounted_fds.contains_key(&fd) {
+            return Err(FileSystemError::CrossDevice);
+        }
+        Ok(())
+    }
+
+    // Whether a mount point is at `folder` or anywhere below it. Such a folder can't be
+    // moved or removed, as its mount would be left behind at the old path.
+    fn holds_mount(&self, folder: &Arc<Mutex<INode>>) -> bool {
+        self.mounts.iter().any(|mount| {
+            self.lookup(&mount.at)
+                .is_ok_and(|at| Arc::ptr_eq(&at, folder) || holds_node(folder, &at))
+        })
+    }
+
+    // Fail if opening one more descriptor would go over max_open_fds. Checked before an
+    // operation has any other effect, so allocate_fd itself cannot fail.
+    fn check_fd_limit(&self) -> Result<(), FileSystemError> {
+        match self.max_open_fds {
+            Some(max) if self.file_descriptors.len() + self.mounted_fds.len() >= max => {
+                Err(FileSystemError::TooManyOpenFiles)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Whether `to` names the very entry `from` does, spelled in a different case, as it
+    // can when names are matched regardless of case
+    fn is_case_change(&self, from: &[String], to: &[String]) -> Result<bool, FileSystemError> {
+        let parent_of = |components: &[String]| self.lookup(&components[..components.len() - 1]);
+        if !Arc::ptr_eq(&parent_of(from)?, &parent_of(to)?) {
+            return Ok(false);
+        }
+        let stored = self.backend.entry_name(from)?;
+        Ok(stored == self.backend.entry_name(to)? && Some(&stored) != to.last())
+    }
+
+    fn take_fd_number(&mut self) -> usize {
+        match self.free_fds.pop() {
+            Some(Reverse(fd)) => fd,
+            None => {
+                self.next_fd += 1;
+                self.next_fd - 1
+            }
+        }
+    }
+
+    // Number a descriptor opened inside a mount like any of our own
+    fn adopt_mounted_fd(&mut self, index: usize, inner_fd: usize) -> usize {
+        let fd = self.take_fd_number();
+        self.mounted_fds.insert(fd, (index, inner_fd));
+        fd
+    }
+
+    // The mount `path` leads into, if any, by index, with the path inside it. The
+    // deepest mount point wins when mounts are nested.
+    fn mount_for(&self, path: &str) -> Option<(usize, String)> {
+        let components = self.split_path(path);
+        self.mounts
+            .iter()
+            .enumerate()
+            .filter(|(_, mount)| components.starts_with(&mount.at))
+            .max_by_key(|(_, mount)| mount.at.len())
+            .map(|(index, mount)| (index, path_of(&components[mount.at.len()..])))
+    }
+
+    // Attach `fs` at the folder `path`, hiding what the folder holds until the file
+    // system is dropped. The FileSystem methods, and the descriptors they hand out,
+    // reach through mount points; the other methods only see this tree. Paths are
+    // matched against mount points as written, so a symlink leading into a mounted
+    // folder is not followed into the mount. Descriptors from a mount also work with
+    // what is built on the FileSystem methods: read_to_end and the other reading
+    // helpers, write_all, writev, append and buf_writer. Those that need the file's
+    // node here instead (read_at, peek, write_at, dup, reopen, readdir, lock, unlock,
+    // reader and writer) fail with CrossDevice. `fs` must be Send and Sync because
+    // this file system owns it and has to stay both: write_at is called from several
+    // threads at once, and the async wrapper moves the file system between tasks.
+    fn mount_at(
+        &mut self,
+        path: &str,
+        fs: Box<dyn FileSystem + Send + Sync>,
+    ) -> Result<(), FileSystemError> {
+        let at = self.split_path(path);
+        if self.mounts.iter().any(|mount| mount.at == at) {
+            return Err(FileSystemError::FileExists);
+        }
+        let folder = self.lookup(&at)?;
+        if !matches!(&*lock_node(&folder)?, INode::Folder { .. }) {
+            return Err(FileSystemError::InvalidType);
+        }
+        self.mounts.push(Mount { at, fs });
+        Ok(())
+    }
+
+    fn allocate_fd(
+        &mut self,
+        inode: Arc<Mutex<INode>>,
+        path: Vec<String>,
+        flags: OpenFlags,
+    ) -> usize {
+        let fd = self.take_fd_number();
+        self.file_descriptors.insert(
+            fd,
+            FileDescriptor {
+                inode,
+                path,
+                position: 0,
+                flags,
+                lock: None,
+            },
+        );
+        fd
+    }
+
+    // Split a path into its components, starting from the working directory unless the
+    // path is absolute
+    fn split_path(&self, path: &str) -> Vec<String> {
+        if path.starts_with('/') {
+            join_path(Vec::new(), path)
+        } else {
+            join_path(self.cwd.clone(), path)
+        }
+    }
+
+    // Open a second descriptor on the same file. It starts at the original's position
+    // but moves independently from then on.
+    fn dup(&mut self, fd: usize) -> Result<usize, FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        let (inode, path, position, flags) = (
+            file_desc.inode.clone(),
+            file_desc.path.clone(),
+            file_desc.position,
+            file_desc.flags,
+        );
+
+        self.check_fd_limit()?;
+        let new_fd = self.allocate_fd(inode, path, flags);
+        self.file_descriptors.get_mut(&new_fd).unwrap().position = position;
+        Ok(new_fd)
+    }
+
+    // Point an open descriptor at another file, keeping its number and flags but
+    // starting again from the beginning, like freopen. Any lock it held on the old file
+    // is given up.
+    fn reopen(&mut self, fd: usize, path: &str) -> Result<(), FileSystemError> {
+        self.check_own_fd(fd)?;
+        if !self.file_descriptors.contains_key(&fd) {
+            return Err(FileSystemError::InvalidFileDescriptor);
+        }
+        // Our descriptors can only hold nodes of this tree
+        if self.mount_for(path).is_some() {
+            return Err(FileSystemError::CrossDevice);
+        }
+        let inode = self.get_inode(path)?;
+        #[cfg(feature = "compression")]
+        lock_node(&inode)?.inflate()?;
+
+        let components = self.split_path(path);
+        let file_desc = self.file_descriptors.get_mut(&fd).unwrap();
+        let old = FileDescriptor {
+            inode: std::mem::replace(&mut file_desc.inode, inode),
+            path: std::mem::replace(&mut file_desc.path, components),
+            position: std::mem::take(&mut file_desc.position),
+            flags: file_desc.flags,
+            lock: file_desc.lock.take(),
+        };
+        self.let_go(old);
+        Ok(())
+    }
+
+    // Give up what a descriptor no longer in the table held on its file. This always
+    // succeeds, so a descriptor is never left half closed.
+    fn let_go(&mut self, file_desc: FileDescriptor) {
+        if let INode::File { lock, .. } = &mut *lock_node_or_recover(&file_desc.inode) {
+            *lock = lock.without(file_desc.lock);
+        }
+        self.release_if_orphaned(&file_desc.inode);
+        #[cfg(feature = "compression")]
+        if self.compress && !self.is_open(&file_desc.inode) {
+            if let INode::File { data, packed, .. } = &mut *lock_node_or_recover(&file_desc.inode) {
+                // Left uncompressed if deflating fails; it is tried again on the next close
+                if let Ok(stored) = Packed::new(data) {
+                    *data = Arc::default();
+                    *packed = Some(stored);
+                }
+            }
+        }
+    }
+
+    // Walk the tree from the root, returning the shared handle of the node at the end.
+    // Symlinks met along the way are followed, including one at the end of the path.
+    fn lookup(&self, components: &[String]) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        self.backend.resolve(components, true)
+    }
+
+    fn get_inode(&self, path: &str) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        let components = self.split_path(path);
+        let inode = self.lookup(&components)?;
+        let is_file = matches!(&*lock_node(&inode)?, INode::File { .. });
+        if is_file {
+            Ok(inode)
+        } else {
+            Err(FileSystemError::InvalidType)
+        }
+    }
+
+    // An empty file, not yet named by any folder
+    fn new_file_node(&self, permissions: Permissions) -> INode {
+        INode::File {
+            data: Arc::default(),
+            permissions,
+            links: 1,
+            lock: LockState::Unlocked,
+            times: Timestamps::new(self.clock.now()),
+            ino: next_ino(),
+            xattrs: HashMap::new(),
+            history: VecDeque::new(),
+            #[cfg(feature = "compression")]
+            packed: None,
+        }
+    }
+
+    // Insert a new node under its parent folder, which must already exist
+    fn insert_node(
+        &mut self,
+        path: &str,
+        node: INode,
+    ) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        let inode = Arc::new(Mutex::new(node));
+        self.insert_entry(path, inode.clone())?;
+        Ok(inode)
+    }
+
+    // Add a directory entry naming an existing node
+    fn insert_entry(
+        &mut self,
+        path: &str,
+        inode: Arc<Mutex<INode>>,
+    ) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        let components = self.split_path(path);
+        self.validate_name(&components)?;
+        self.check_parent_writable(&components)?;
+        self.backend.insert(&components, inode)?;
+        self.emit(FsEvent::Created(path_of(&components)));
+        Ok(())
+    }
+
+    // Detach a node from its parent folder once `check` accepts it. Descriptors still
+    // holding the node keep it alive, so open files survive being unlinked.
+    fn remove_node(
+        &mut self,
+        path: &str,
+        check: impl Fn(&INode) -> Result<(), FileSystemError>,
+    ) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+        self.check_writable()?;
+        let components = self.split_path(path);
+        self.check_parent_writable(&components)?;
+        let removed = self.backend.remove(&components, &check)?;
+        self.layout = next_layout();
+        self.emit(FsEvent::Removed(path_of(&components)));
+        Ok(removed)
+    }
+
+    // Create every missing folder along the path, leaving existing folders untouched
+    fn create_dir_all(
+        &mut self,
+        path: &str,
+        permissions: Permissions,
+    ) -> Result<(), FileSystemError> {
+        let components = self.split_path(path);
+        let intermediate = components.len().saturating_sub(1);
+        self.create_folders(&components, &permissions, intermediate)
+    }
+
+    // Make every missing folder along `components`. The first `intermediate` of them
+    // inherit their parent's permissions when that is turned on; the rest get
+    // `permissions`.
+    fn create_folders(
+        &mut self,
+        components: &[String],
+        permissions: &Permissions,
+        intermediate: usize,
+    ) -> Result<(), FileSystemError> {
+        // Permissions of the deepest folder found or made so far
+        let mut parent_permissions = None;
+        for depth in 0..=components.len() {
+            let prefix = &components[..depth];
+            let node = match self.lookup(prefix) {
+                Ok(node) => node,
+                Err(FileSystemError::FileNotFound) => {
+                    self.check_writable()?;
+                    self.validate_name(prefix)?;
+                    self.check_parent_writable(prefix)?;
+                    let permissions = match parent_permissions.take() {
+                        Some(inherited) if self.inherit_permissions && depth <= intermediate => {
+                            inherited
+                        }
+                        _ => permissions.clone(),
+                    };
+                    let folder = Arc::new(Mutex::new(INode::Folder {
+                        contents: HashMap::new(),
+                        permissions,
+                        times: Timestamps::new(self.clock.now()),
+                        ino: next_ino(),
+                        xattrs: HashMap::new(),
+                    }));
+                    self.backend.insert(prefix, folder.clone())?;
+                    self.emit(FsEvent::Created(path_of(prefix)));
+                    folder
+                }
+                Err(e) => return Err(e),
+            };
+            let node = lock_node(&node)?;
+            match &*node {
+                INode::Folder { permissions, .. } => {
+                    parent_permissions = Some(permissions.clone());
+                }
+                INode::File { .. } | INode::Symlink { .. } => {
+                    return Err(FileSystemError::InvalidType);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Remove a folder and everything beneath it. Every folder in the tree, and the one
+    // holding it, has to be writable; nothing is removed unless they all are. A folder holding a mount point
+    // fails with CrossDevice.
+    fn remove_dir_all(&mut self, path: &str) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        let components = self.split_path(path);
+        if components.is_empty() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let folder = self.backend.resolve(&components, false)?;
+        if !matches!(&*lock_node(&folder)?, INode::Folder { .. }) {
+            return Err(FileSystemError::InvalidType);
+        }
+        if self.holds_mount(&folder) {
+            return Err(FileSystemError::CrossDevice);
+        }
+        self.check_parent_writable(&components)?;
+        let mut files = Vec::new();
+        collect_removable_files(&folder, &mut files)?;
+
+        self.backend.remove(&components, &|_| Ok(()))?;
+        self.layout = next_layout();
+        self.emit(FsEvent::Removed(path_of(&components)));
+        for file in files {
+            lock_node(&file)?.drop_link();
+            self.release_if_orphaned(&file);
+        }
+        Ok(())
+    }
+
+    // Render the whole tree like the `tree` command, one entry per line with its size
+    // and permissions. Folders come before files and symlinks are shown, not followed.
+    fn print_tree(&self) -> String {
+        let mut out = String::from("/\n");
+        if let Ok(root) = self.lookup(&[]) {
+            render_tree(&root, "", &mut out);
+        }
+        out
+    }
+
+    // Total bytes of file data under a path, like `du`. A file reached through several
+    // hard links is only counted once, and symlinks inside the tree are not followed.
+    fn disk_usage(&self, path: &str) -> Result<usize, FileSystemError> {
+        let components = self.split_path(path);
+        let node = self.lookup(&components)?;
+        let mut seen = HashSet::new();
+        Ok(usage_of(&node, &mut seen))
+    }
+
+    // Check whether the node at `path` allows `mode` without opening it, like access(2).
+    // Asking for write access on a read-only mount fails even where the node allows it.
+    fn access(&self, path: &str, mode: Permissions) -> Result<(), FileSystemError> {
+        let node = self.lookup(&self.split_path(path))?;
+        let permissions = lock_node(&node)?.metadata().permissions;
+        if mode.can_write() {
+            self.check_writable()?;
+        }
+        if (mode.can_read() && !permissions.can_read())
+            || (mode.can_write() && !permissions.can_write())
+        {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Look for broken invariants in the tree and descriptor table, like fsck. Problems
+    // are reported rather than repaired.
+    fn fsck(&self) -> Vec<FsckProblem> {
+        let mut problems = Vec::new();
+        let mut files = HashMap::new();
+        if let Ok(root) = self.lookup(&[]) {
+            check_tree(
+                &root,
+                &mut Vec::new(),
+                &mut HashSet::new(),
+                &mut files,
+                &mut problems,
+            );
+        }
+
+        let mut held = 0;
+        for (path, file, entries) in files.values() {
+            let node = lock_node_or_recover(file);
+            held += node.file_len();
+            let links = node.metadata().links;
+            if links != *entries {
+                problems.push(FsckProblem::LinkCount {
+                    path: path.clone(),
+                    links,
+                    entries: *entries,
+                });
+            }
+        }
+
+        // Open files that are no longer in the tree still hold their data
+        let mut seen = HashSet::new();
+        let mut fds: Vec<_> = self.file_descriptors.iter().collect();
+        fds.sort_by_key(|(fd, _)| **fd);
+        for (fd, desc) in fds {
+            let ptr = Arc::as_ptr(&desc.inode);
+            if files.contains_key(&ptr) || !seen.insert(ptr) {
+                continue;
+            }
+            let node = lock_node_or_recover(&desc.inode);
+            held += node.file_len();
+            if node.metadata().links > 0 {
+                problems.push(FsckProblem::DanglingDescriptor(*fd));
+            }
+        }
+
+        let counted = self.total_bytes.load(Ordering::SeqCst);
+        if counted != held {
+            problems.push(FsckProblem::ByteCount { counted, held });
+        }
+        problems
+    }
+
+    // Counts for the whole tree, like `df`, gathered in a single pass
+    fn statfs(&self) -> FsStats {
+        let mut stats = FsStats::default();
+        if let Ok(root) = self.lookup(&[]) {
+            tally(&root, &mut HashSet::new(), &mut stats);
+        }
+        stats.bytes_free = self
+            .max_total_bytes
+            .map(|max| max.saturating_sub(self.total_bytes.load(Ordering::SeqCst)));
+        stats
+    }
+
+    // Estimate of the memory the tree takes: file contents and kept versions, plus a
+    // fixed cost per node and per folder entry. The same tree always gives the same
+    // figure.
+    fn memory_usage(&self) -> usize {
+        self.lookup(&[])
+            .map_or(0, |root| footprint(&root, &mut HashSet::new()))
+    }
+
+    // Give the allocator back the spare capacity file buffers keep after shrinking,
+    // returning how many bytes that frees. Files only open through descriptors are
+    // included.
+    fn compact(&mut self) -> usize {
+        let mut seen = HashSet::new();
+        let in_tree = self
+            .lookup(&[])
+            .map_or(0, |root| compact_node(&root, &mut seen));
+        let open: usize = self
+            .file_descriptors
+            .values()
+            .map(|desc| compact_node(&desc.inode, &mut seen))
+            .sum();
+        in_tree + open
+    }
+
+    // Number of entries directly inside a folder, without collecting their names
+    fn entry_count(&self, path: &str) -> Result<usize, FileSystemError> {
+        let folder = self.lookup(&self.split_path(path))?;
+        let folder = lock_node(&folder)?;
+        match &*folder {
+            INode::Folder { contents, .. } => Ok(contents.len()),
+            INode::File { .. } | INode::Symlink { .. } => Err(FileSystemError::InvalidType),
+        }
+    }
+
+    // Every path under the folder `root` with its metadata, in sorted pre-order. The
+    // iterator holds its own handles on the nodes rather than a borrow of the file
+    // system, so the tree can change while it runs; entries added or removed in folders
+    // it has not reached yet show up or disappear accordingly.
+    fn walk(
+        &self,
+        root: &str,
+    ) -> Result<impl Iterator<Item = (String, Metadata)>, FileSystemError> {
+        let components = self.split_path(root);
+        let folder = self.lookup(&components)?;
+        if !matches!(&*lock_node(&folder)?, INode::Folder { .. }) {
+            return Err(FileSystemError::InvalidType);
+        }
+        let mut walk = Walk {
+            pending: Vec::new(),
+        };
+        walk.push_children(&components, &folder);
+        Ok(walk)
+    }
+
+    // Absolute paths of everything under `root` whose name matches `pattern`, sorted.
+    // The pattern supports `*` for any run of characters and `?` for a single one.
+    fn find(&self, root: &str, pattern: &str) -> Result<Vec<String>, FileSystemError> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let mut matches: Vec<String> = self
+            .walk(root)?
+            .map(|(path, _)| path)
+            .filter(|path| {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                glob_match(&pattern, &name.chars().collect::<Vec<_>>())
+            })
+            .collect();
+        matches.sort();
+        Ok(matches)
+    }
+
+    // Copy a directory from the host into the tree below `mount_at`, which is created if
+    // missing. Host permission bits map to Permissions as in PersistentFileSystem.
+    // Symlinks are either followed, with their targets copied in their place, or
+    // skipped.
+    fn import_dir<P: AsRef<Path>>(
+        &mut self,
+        host_dir: P,
+        mount_at: &str,
+        follow_symlinks: bool,
+    ) -> Result<(), FileSystemError> {
+        self.create_dir_all(mount_at, Permissions::ReadWrite)?;
+        let mut visited = HashSet::new();
+        self.import_entries(host_dir.as_ref(), mount_at, follow_symlinks, &mut visited)
+    }
+
+    // Copy the entries of one host directory for import_dir. `visited` holds the host
+    // directories currently being copied.
+    fn import_entries(
+        &mut self,
+        host_dir: &Path,
+        dest: &str,
+        follow_symlinks: bool,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Result<(), FileSystemError> {
+        let canonical = host_dir.canonicalize()?;
+        visited.insert(canonical.clone());
+        let mut entries = fs::read_dir(host_dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name.to_str().ok_or(FileSystemError::InvalidName)?;
+            let path = format!("{}/{}", dest.trim_end_matches('/'), name);
+            let host = entry.path();
+            let mut meta = fs::symlink_metadata(&host)?;
+            if meta.file_type().is_symlink() {
+                if !follow_symlinks {
+                    continue;
+                }
+                meta = fs::metadata(&host)?;
+            }
+
+            if meta.is_dir() {
+                // A symlink back to a directory being copied would never end
+                if visited.contains(&host.canonicalize()?) {
+                    continue;
+                }
+                self.mkdir(&path, Permissions::ReadWrite)?;
+                self.import_entries(&host, &path, follow_symlinks, visited)?;
+                self.chmod(&path, permissions_from_host(&meta))?;
+            } else {
+                self.write_new_file(&path, &fs::read(&host)?, permissions_from_host(&meta))?;
+            }
+        }
+        visited.remove(&canonical);
+        Ok(())
+    }
+
+    // Create a file holding `data`. It is filled before its permissions are applied,
+    // since those may forbid writing.
+    fn write_new_file(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        permissions: Permissions,
+    ) -> Result<(), FileSystemError> {
+        let fd = self.create(path, Permissions::ReadWrite)?;
+        let written = self.write_all(fd, data);
+        self.close(fd)?;
+        written?;
+        self.chmod(path, permissions)
+    }
+
+    // Write the folder `subtree` and everything beneath it into `host_dir` on the host,
+    // which is created if missing. Hard links are written as independent files, and
+    // symlinks keep their targets exactly as stored.
+    fn export_dir<P: AsRef<Path>>(
+        &self,
+        subtree: &str,
+        host_dir: P,
+    ) -> Result<(), FileSystemError> {
+        let host_dir = host_dir.as_ref();
+        let base = self.split_path(subtree);
+        fs::create_dir_all(host_dir)?;
+        // Permissions go on last, deepest first, so a read-only folder is filled first
+        let mut permissions = Vec::new();
+        for (path, metadata) in self.walk(subtree)? {
+            let components = self.split_path(&path);
+            let host: PathBuf = std::iter::once(host_dir)
+                .chain(components[base.len()..].iter().map(Path::new))
+                .collect();
+            let node = self.backend.resolve(&components, false)?;
+            let node = lock_node(&node)?;
+            match &*node {
+                INode::Folder { .. } => fs::create_dir(&host)?,
+                file @ INode::File { .. } => fs::write(&host, file.file_contents()?)?,
+                INode::Symlink { target, .. } => {
+                    create_host_symlink(target, &host)?;
+                    continue;
+                }
+            }
+            permissions.push((host, metadata));
+        }
+        for (host, metadata) in permissions.iter().rev() {
+            set_host_permissions(host, &metadata.permissions, metadata.is_dir)?;
+        }
+        Ok(())
+    }
+
+    // Duplicate a file into a new, independent file at `to`
+    fn copy(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let source = self.get_inode(from)?;
+        let copied = match &*lock_node(&source)? {
+            file @ INode::File {
+                data,
+                permissions,
+                #[cfg(feature = "compression")]
+                packed,
+                ..
+            } => {
+                if !permissions.can_read() {
+                    return Err(FileSystemError::PermissionDenied);
+                }
+                self.reserve_bytes(file.file_len())?;
+                INode::File {
+                    data: data.clone(),
+                    permissions: permissions.clone(),
+                    links: 1,
+                    lock: LockState::Unlocked,
+                    times: Timestamps::new(self.clock.now()),
+                    ino: next_ino(),
+                    xattrs: HashMap::new(),
+                    history: VecDeque::new(),
+                    #[cfg(feature = "compression")]
+                    packed: packed.clone(),
+                }
+            }
+            _ => return Err(FileSystemError::InvalidType),
+        };
+        let size = copied.metadata().size;
+        if let Err(e) = self.insert_node(to, copied) {
+            self.release_bytes(size);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Clone a file or a whole folder to `to` without copying any file data. The clone
+    // shares its buffers with the source until either side writes to one.
+    fn clone_cow(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let source = self.lookup(&self.split_path(from))?;
+        match &*lock_node(&source)? {
+            INode::File { permissions, .. } | INode::Folder { permissions, .. } => {
+                if !permissions.can_read() {
+                    return Err(FileSystemError::PermissionDenied);
+                }
+            }
+            INode::Symlink { .. } => return Err(FileSystemError::InvalidType),
+        }
+
+        let clone = deep_clone(&source, &mut HashMap::new());
+        recount_links(&clone);
+
+        let size = usage_of(&clone, &mut HashSet::new());
+        self.reserve_bytes(size)?;
+        if let Err(e) = self.insert_entry(to, clone) {
+            self.release_bytes(size);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Bytes a file's contents take up as stored, which is less than its size once the
+    // file has been compressed
+    #[cfg(feature = "compression")]
+    fn compressed_size(&self, path: &str) -> Result<usize, FileSystemError> {
+        let inode = self.get_inode(path)?;
+        let node = lock_node(&inode)?;
+        match &*node {
+            INode::File {
+                packed: Some(packed),
+                ..
+            } => Ok(packed.bytes.len()),
+            INode::File { data, .. } => Ok(data.len()),
+            _ => Err(FileSystemError::InvalidType),
+        }
+    }
+
+    // 64-bit FNV-1a hash of a file's contents. The algorithm is fixed, so a checksum
+    // taken before saving the tree can be checked against it after loading.
+    fn checksum(&self, path: &str) -> Result<u64, FileSystemError> {
+        let inode = self.get_inode(path)?;
+        let node = lock_node(&inode)?;
+        if let INode::File { permissions, .. } = &*node {
+            if !permissions.can_read() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+        }
+        Ok(fnv1a(&node.file_contents()?))
+    }
+
+    fn verify(&self, path: &str, expected: u64) -> Result<bool, FileSystemError> {
+        Ok(self.checksum(path)? == expected)
+    }
+
+    // Compare two files byte by byte
+    fn diff(&self, path_a: &str, path_b: &str) -> Result<FileDiff, FileSystemError> {
+        // Each file is locked on its own, since both paths may name the same one
+        let contents = |path: &str| -> Result<Vec<u8>, FileSystemError> {
+            let inode = self.get_inode(path)?;
+            let node = lock_node(&inode)?;
+            if !node.metadata().permissions.can_read() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+            Ok(node.file_contents()?.into_owned())
+        };
+        let (a, b) = (contents(path_a)?, contents(path_b)?);
+        Ok(FileDiff {
+            changed: a
+                .iter()
+                .zip(b.iter())
+                .enumerate()
+                .filter(|(_, (x, y))| x != y)
+                .map(|(offset, (&x, &y))| (offset, x, y))
+                .collect(),
+            extra_bytes: a.len() != b.len(),
+        })
+    }
+
+    // Attach a named value to a file or folder, replacing any earlier value of `key`.
+    // Extended attributes follow the node's permissions: writing one needs write
+    // access and reading one needs read access.
+    fn set_xattr(&mut self, path: &str, key: &str, value: &[u8]) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        let inode = self.lookup(&self.split_path(path))?;
+        let mut node = lock_node(&inode)?;
+        if !node.metadata().permissions.can_write() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        node.xattrs_mut().insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn get_xattr(&self, path: &str, key: &str) -> Result<Vec<u8>, FileSystemError> {
+        let inode = self.lookup(&self.split_path(path))?;
+        let node = lock_node(&inode)?;
+        if !node.metadata().permissions.can_read() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        node.xattrs()
+            .get(key)
+            .cloned()
+            .ok_or(FileSystemError::XattrNotFound)
+    }
+
+    // Names of a node's extended attributes, sorted
+    fn list_xattr(&self, path: &str) -> Result<Vec<String>, FileSystemError> {
+        let inode = self.lookup(&self.split_path(path))?;
+        let node = lock_node(&inode)?;
+        if !node.metadata().permissions.can_read() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let mut keys: Vec<String> = node.xattrs().keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn remove_xattr(&mut self, path: &str, key: &str) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        let inode = self.lookup(&self.split_path(path))?;
+        let mut node = lock_node(&inode)?;
+        if !node.metadata().permissions.can_write() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        node.xattrs_mut()
+            .remove(key)
+            .map(|_| ())
+            .ok_or(FileSystemError::XattrNotFound)
+    }
+
+    // Create an empty file at `path`, or mark whatever is already there as just
+    // accessed and modified. No descriptor is left open either way.
+    fn touch(&mut self, path: &str) -> Result<(), FileSystemError> {
+        match self.lookup(&self.split_path(path)) {
+            Ok(node) => {
+                self.check_writable()?;
+                let now = self.clock.now();
+                let mut node = lock_node(&node)?;
+                // Setting the times to now takes write permission, as utimensat does
+                if !node.metadata().permissions.can_write() {
+                    return Err(FileSystemError::PermissionDenied);
+                }
+                let times = node.times_mut();
+                times.accessed = now;
+                times.modified = now;
+            }
+            Err(FileSystemError::FileNotFound) => {
+                self.insert_node(
+                    path,
+                    INode::File {
+                        data: Arc::default(),
+                        permissions: Permissions::ReadWrite,
+                        links: 1,
+                        lock: LockState::Unlocked,
+                        times: Timestamps::new(self.clock.now()),
+                        ino: next_ino(),
+                        xattrs: HashMap::new(),
+                        history: VecDeque::new(),
+                        #[cfg(feature = "compression")]
+                        packed: None,
+                    },
+                )?;
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    // Create a new file in `dir` named `prefix` plus a counter, like mkstemp, and return
+    // its path with a read-write descriptor. Names already taken are skipped, so the
+    // file is always new, and the same sequence of calls always picks the same names.
+    fn mktemp(&mut self, dir: &str, prefix: &str) -> Result<(String, usize), FileSystemError> {
+        if prefix.contains('/') {
+            return Err(FileSystemError::InvalidName);
+        }
+        loop {
+            let path = format!(
+                "{}/{}{:06}",
+                dir.trim_end_matches('/'),
+                prefix,
+                self.next_temp
+            );
+            self.next_temp += 1;
+            let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCL;
+            match self.open_with(&path, flags) {
+                Ok(fd) => return Ok((path, fd)),
+                Err(FileSystemError::FileExists) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Number of earlier versions of a file that revert can bring back
+    fn history_len(&self, path: &str) -> Result<usize, FileSystemError> {
+        let inode = self.get_inode(path)?;
+        let node = lock_node(&inode)?;
+        match &*node {
+            INode::File { history, .. } => Ok(history.len()),
+            _ => Err(FileSystemError::InvalidType),
+        }
+    }
+
+    // Bring back an earlier version of a file, 1 being the contents before the latest
+    // change. Reverting is itself a change, so the contents it replaces become the
+    // newest version.
+    fn revert(&mut self, path: &str, n: usize) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        let inode = self.get_inode(path)?;
+        let mut node = lock_node(&inode)?;
+        #[cfg(feature = "compression")]
+        node.inflate()?;
+        if let INode::File {
+            data,
+            permissions,
+            times,
+            history,
+            ..
+        } = &mut *node
+        {
+            if !permissions.can_write() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+            let version = n
+                .checked_sub(1)
+                .and_then(|index| history.get(index))
+                .map(|version| version.data.clone())
+                .ok_or(FileSystemError::VersionNotFound)?;
+            if version.len() > data.len() {
+                self.check_file_size(version.len())?;
+                self.reserve_bytes(version.len() - data.len())?;
+            } else {
+                self.release_bytes(data.len() - version.len());
+            }
+            self.keep_version(history, data, times.modified);
+            *data = version;
+            self.share_data(data);
+            times.modified = self.clock.now();
+        }
+        self.emit(FsEvent::Written(path_of(&self.split_path(path))));
+        Ok(())
+    }
+
+    // A file's contents as they were at `at`: the newest version last modified no later
+    // than that. When even the oldest version kept is newer, that oldest one is given.
+    fn read_version_at(&self, path: &str, at: SystemTime) -> Result<Vec<u8>, FileSystemError> {
+        let inode = self.get_inode(path)?;
+        let node = lock_node(&inode)?;
+        let INode::File {
+            times,
+            history,
+            permissions,
+            ..
+        } = &*node
+        else {
+            return Err(FileSystemError::InvalidType);
+        };
+        // Earlier versions are as private as the current one
+        if !permissions.can_read() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        if times.modified <= at {
+            return Ok(node.file_contents()?.into_owned());
+        }
+        match history
+            .iter()
+            .find(|version| version.modified <= at)
+            .or(history.back())
+        {
+            Some(version) => Ok(version.data.to_vec()),
+            // Without any versions kept, the current contents are all there is
+            None => Ok(node.file_contents()?.into_owned()),
+        }
+    }
+
+    // Add a second name for an existing file. Both names share one inode, so writes
+    // through either are visible through the other.
+    fn link(&mut self, existing: &str, new_path: &str) -> Result<(), FileSystemError> {
+        let inode = self.get_inode(existing)?;
+        self.insert_entry(new_path, inode.clone())?;
+        if let INode::File { links, .. } = &mut *lock_node(&inode)? {
+            *links += 1;
+        }
+        Ok(())
+    }
+
+    // Create a symlink at `link_path`. The target is stored as given and only has to
+    // exist once the link is followed.
+    fn symlink(&mut self, target: &str, link_path: &str) -> Result<(), FileSystemError> {
+        self.insert_node(
+            link_path,
+            INode::Symlink {
+                target: target.to_string(),
+                permissions: Permissions::ReadWrite,
+                times: Timestamps::new(self.clock.now()),
+                ino: next_ino(),
+                xattrs: HashMap::new(),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn readlink(&self, path: &str) -> Result<String, FileSystemError> {
+        let components = self.split_path(path);
+        let node = self.backend.resolve(&components, false)?;
+        let node = lock_node(&node)?;
+        match &*node {
+            INode::Symlink { target, .. } => Ok(target.clone()),
+            _ => Err(FileSystemError::InvalidType),
+        }
+    }
+
+    // The absolute path of what `path` names once every symlink along it is replaced by
+    // its target, like std::fs::canonicalize. `.` and `..` are handled as in every
+    // other path, before any links are followed.
+    fn canonicalize(&self, path: &str) -> Result<String, FileSystemError> {
+        let mut pending = self.split_path(path);
+        let mut hops = 0;
+        'restart: loop {
+            for depth in 1..=pending.len() {
+                let node = self.backend.resolve(&pending[..depth], false)?;
+                let target = match &*lock_node(&node)? {
+                    INode::Symlink { target, .. } => target.clone(),
+                    _ => continue,
+                };
+                hops += 1;
+                if hops > MAX_SYMLINK_DEPTH {
+                    return Err(FileSystemError::TooManySymlinks);
+                }
+                // Everything before the link is already free of links, so resolution
+                // starts over on the substituted path
+                let base = if target.starts_with('/') {
+                    Vec::new()
+                } else {
+                    pending[..depth - 1].to_vec()
+                };
+                let mut resolved = join_path(base, &target);
+                resolved.extend_from_slice(&pending[depth..]);
+                pending = resolved;
+                continue 'restart;
+            }
+            return Ok(path_of(&pending));
+        }
+    }
+
+    fn chdir(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let components = self.split_path(path);
+        let node = self.lookup(&components)?;
+        let is_folder = matches!(&*lock_node(&node)?, INode::Folder { .. });
+        if !is_folder {
+            return Err(FileSystemError::InvalidType);
+        }
+        self.cwd = components;
+        Ok(())
+    }
+
+    fn getcwd(&self) -> String {
+        path_of(&self.cwd)
+    }
+
+    // Read everything from the descriptor's position to the end of the file
+    fn read_to_end(&mut self, fd: usize) -> Result<Vec<u8>, FileSystemError> {
+        let remaining = if self.mounted_fds.contains_key(&fd) {
+            let metadata = self.fstat(fd)?;
+            if metadata.is_dir {
+                return Err(FileSystemError::InvalidType);
+            }
+            metadata.size.saturating_sub(self.tell(fd)?)
+        } else {
+            let file_desc = self
+                .file_descriptors
+                .get(&fd)
+                .ok_or(FileSystemError::InvalidFileDescriptor)?;
+            match &*lock_node(&file_desc.inode)? {
+                INode::File { data, .. } => data.len().saturating_sub(file_desc.position),
+                _ => return Err(FileSystemError::InvalidType),
+            }
+        };
+
+        let mut buffer = vec![0; remaining];
+        let bytes_read = self.read(fd, &mut buffer)?;
+        buffer.truncate(bytes_read);
+        Ok(buffer)
+    }
+
+    fn read_to_string(&mut self, fd: usize) -> Result<String, FileSystemError> {
+        String::from_utf8(self.read_to_end(fd)?).map_err(|_| FileSystemError::InvalidUtf8)
+    }
+
+    // Next entry of an open folder in name order, or None once every entry has been
+    // returned. Entries added or removed between calls may be skipped or repeated.
+    fn readdir(&mut self, fd: usize) -> Result<Option<DirEntry>, FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get_mut(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        let mut entries: Vec<(String, Arc<Mutex<INode>>)> = match &*lock_node(&file_desc.inode)? {
+            INode::Folder { contents, .. } => contents
+                .iter()
+                .map(|(name, node)| (name.clone(), node.clone()))
+                .collect(),
+            _ => return Err(FileSystemError::InvalidType),
+        };
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let Some((name, node)) = entries.into_iter().nth(file_desc.position) else {
+            return Ok(None);
+        };
+        file_desc.position += 1;
+        let is_dir = matches!(&*lock_node(&node)?, INode::Folder { .. });
+        Ok(Some(DirEntry { name, is_dir }))
+    }
+
+    // Lines from the current position to the end of the file, without their trailing
+    // "\n". A line that is not valid UTF-8 is yielded as an InvalidUtf8 error.
+    fn lines(
+        &mut self,
+        fd: usize,
+    ) -> Result<impl Iterator<Item = Result<String, FileSystemError>>, FileSystemError> {
+        let data = self.read_to_end(fd)?;
+        let mut lines: Vec<Vec<u8>> = data
+            .split(|&byte| byte == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect();
+        // Splitting leaves an empty piece after a final newline, or for an empty file
+        if data.is_empty() || data.ends_with(b"\n") {
+            lines.pop();
+        }
+        Ok(lines
+            .into_iter()
+            .map(|line| String::from_utf8(line).map_err(|_| FileSystemError::InvalidUtf8)))
+    }
+
+    // Write the whole slice or fail, like std::io::Write::write_all. An empty slice
+    // succeeds without touching the file, even when the position is past its end
+    fn write_all(&mut self, fd: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        if !self.file_descriptors.contains_key(&fd) && !self.mounted_fds.contains_key(&fd) {
+            return Err(FileSystemError::InvalidFileDescriptor);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.write(fd, data)
+    }
+
+    // Write several buffers back to back as one write, like writev(2), so no other
+    // write can land between them. Returns the total bytes written.
+    fn writev(&mut self, fd: usize, bufs: &[&[u8]]) -> Result<usize, FileSystemError> {
+        let data = bufs.concat();
+        self.write(fd, &data)?;
+        Ok(data.len())
+    }
+
+    // Write at the end of the file whatever the position, as a write in append mode
+    // does, and return the offset the data starts at. Records appended to a log can be
+    // found again by that offset.
+    fn append(&mut self, fd: usize, data: &[u8]) -> Result<usize, FileSystemError> {
+        // Inside a mount there is no write_at, so the end is sought and written at
+        if self.mounted_fds.contains_key(&fd) {
+            let start = self.seek(fd, OffsetFrom::End(0))?;
+            self.write(fd, data)?;
+            return Ok(start);
+        }
+        let start = self.fstat(fd)?.size;
+        self.write_at(fd, start, data)?;
+        self.file_descriptors.get_mut(&fd).unwrap().position = start + data.len();
+        Ok(start)
+    }
+
+    // Fill several buffers in order from one read at the current position, like
+    // readv(2). The position moves past everything read.
+    fn readv(&mut self, fd: usize, bufs: &mut [&mut [u8]]) -> Result<usize, FileSystemError> {
+        let mut data = vec![0; bufs.iter().map(|buf| buf.len()).sum()];
+        let bytes_read = self.read(fd, &mut data)?;
+        let mut rest = &data[..bytes_read];
+        for buf in bufs.iter_mut() {
+            let len = buf.len().min(rest.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            rest = &rest[len..];
+        }
+        Ok(bytes_read)
+    }
+
+    // Look at the bytes from the current position without consuming them, as parsers
+    // looking ahead need
+    fn peek(&self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        self.read_at(fd, file_desc.position, buffer)
+    }
+
+    // Read from an explicit offset, like pread. The descriptor's position is neither used
+    // nor moved, so several readers can share one descriptor.
+    fn read_at(
+        &self,
+        fd: usize,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<usize, FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        if !file_desc.flags.contains(OpenFlags::READ) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+
+        let mut inode = lock_node(&file_desc.inode)?;
+        if let INode::File {
+            data: file_data,
+            permissions,
+            times,
+            ..
+        } = &mut *inode
+        {
+            if !permissions.can_read() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+            times.accessed = self.clock.now();
+            // Reading at or past the end of the file is not an error, just nothing to read
+            if offset >= file_data.len() {
+                return Ok(0);
+            }
+            // Clamped against what is left rather than computing offset + buffer.len(),
+            // which could overflow for offsets near usize::MAX
+            let len = buffer.len().min(file_data.len() - offset);
+            buffer[..len].copy_from_slice(&file_data[offset..offset + len]);
+            Ok(len)
+        } else {
+            Err(FileSystemError::InvalidType)
+        }
+    }
+
+    // Write at an explicit offset, like pwrite, leaving the descriptor's position alone.
+    // The offset is used even in append mode. Only a shared borrow is needed, so threads
+    // can write disjoint parts of a file through one descriptor; the inode's mutex
+    // orders their writes.
+    fn write_at(&self, fd: usize, offset: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        self.check_writable()?;
+        if !file_desc.flags.contains(OpenFlags::WRITE) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+
+        let mut inode = lock_node(&file_desc.inode)?;
+        if let INode::File {
+            data: file_data,
+            permissions,
+            times,
+            history,
+            ..
+        } = &mut *inode
+        {
+            if !permissions.can_write() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+            // An empty write changes nothing, not even the size of a file it starts
+            // past the end of
+            if data.is_empty() {
+                return Ok(());
+            }
+            // Overwrite from the offset, growing the file only past its end. Starting
+            // past the end leaves a gap that reads back as zeros, as in a sparse write.
+            // No file can reach past usize::MAX bytes
+            let end = offset
+                .checked_add(data.len())
+                .ok_or(FileSystemError::FileTooLarge)?;
+            if end > file_data.len() {
+                self.check_file_size(end)?;
+                let growth = end - file_data.len();
+                self.reserve_bytes(growth)?;
+            }
+            self.keep_version(history, file_data, times.modified);
+            // A buffer shared with a copy or clone is split off here, so only this
+            // file sees the write
+            let contents = Arc::make_mut(file_data);
+            if end > contents.len() {
+                contents.resize(end, 0);
+            }
+            contents[offset..end].copy_from_slice(data);
+            self.share_data(file_data);
+            times.modified = self.clock.now();
+            self.emit(FsEvent::Written(path_of(&file_desc.path)));
+            Ok(())
+        } else {
+            Err(FileSystemError::InvalidType)
+        }
+    }
+
+    // Take an advisory lock on the descriptor's file, like flock. Any number of
+    // descriptors can share a lock, but an exclusive one excludes all others. A
+    // conflicting request fails with WouldBlock and leaves the current lock in place.
+    fn lock(&mut self, fd: usize, exclusive: bool) -> Result<(), FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get_mut(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        let wanted = if exclusive {
+            LockKind::Exclusive
+        } else {
+            LockKind::Shared
+        };
+        if file_desc.lock == Some(wanted) {
+            return Ok(());
+        }
+
+        match &mut *lock_node(&file_desc.inode)? {
+            INode::File { lock, .. } => {
+                // Converting a lock only conflicts with the other holders
+                *lock = match (lock.without(file_desc.lock), wanted) {
+                    (LockState::Unlocked, LockKind::Exclusive) => LockState::Exclusive,
+                    (LockState::Unlocked, LockKind::Shared) => LockState::Shared(1),
+                    (LockState::Shared(n), LockKind::Shared) => LockState::Shared(n + 1),
+                    _ => return Err(FileSystemError::WouldBlock),
+                };
+            }
+            _ => return Err(FileSystemError::InvalidType),
+        }
+        file_desc.lock = Some(wanted);
+        Ok(())
+    }
+
+    fn unlock(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get_mut(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        if let INode::File { lock, .. } = &mut *lock_node(&file_desc.inode)? {
+            *lock = lock.without(file_desc.lock);
+        }
+        file_desc.lock = None;
+        Ok(())
+    }
+
+    // Borrow an open descriptor as a std::io reader. The reader moves the descriptor's
+    // own position, so a later read through `fd` carries on where it stopped.
+    fn reader(&mut self, fd: usize) -> Result<FileReader<'_, B>, FileSystemError> {
+        self.check_handle_access(fd, OpenFlags::READ)?;
+        Ok(FileReader { fs: self, fd })
+    }
+
+    // The same as a writer, for a descriptor opened for writing
+    fn writer(&mut self, fd: usize) -> Result<FileWriter<'_, B>, FileSystemError> {
+        self.check_handle_access(fd, OpenFlags::WRITE)?;
+        Ok(FileWriter { fs: self, fd })
+    }
+
+    fn check_handle_access(&self, fd: usize, access: OpenFlags) -> Result<(), FileSystemError> {
+        self.check_own_fd(fd)?;
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        if !file_desc.flags.contains(access) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Borrow an open descriptor for writing through a buffer of `capacity` bytes
+    fn buf_writer(
+        &mut self,
+        fd: usize,
+        capacity: usize,
+    ) -> Result<BufferedWriter<'_, B>, FileSystemError> {
+        if !self.file_descriptors.contains_key(&fd) && !self.mounted_fds.contains_key(&fd) {
+            return Err(FileSystemError::InvalidFileDescriptor);
+        }
+        Ok(BufferedWriter {
+            fs: self,
+            fd,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+        })
+    }
+}
+
+// Serializable view of a file system: the tree and working directory, but not the
+// descriptor table, which only makes sense for a running instance
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(dead_code)]
+struct FileSystemImage {
+    root: Arc<Mutex<INode>>,
+    cwd: Vec<String>,
+}
+
+#[cfg(feature = "serde")]
+#[allow(dead_code)]
+impl<B: StorageBackend> SimpleFileSystem<B> {
+    // Write the tree as JSON. Hard links are written once per name, so they come back
+    // as independent files.
+    fn save_to_writer<W: io::Write>(&self, writer: W) -> Result<(), FileSystemError> {
+        let image = FileSystemImage {
+            root: self.lookup(&[])?,
+            cwd: self.cwd.clone(),
+        };
+        serde_json::to_writer(writer, &image)
+            .map_err(|e| FileSystemError::Serialization(e.to_string()))
+    }
+
+    // Load a saved tree into `backend`, replacing whatever it held
+    fn load_with_backend<R: io::Read>(reader: R, backend: B) -> Result<Self, FileSystemError> {
+        let image: FileSystemImage = serde_json::from_reader(reader)
+            .map_err(|e| FileSystemError::Serialization(e.to_string()))?;
+        if !matches!(&*lock_node(&image.root)?, INode::Folder { .. }) {
+            return Err(FileSystemError::Serialization(
+                "root is not a folder".to_string(),
+            ));
+        }
+
+        // Hard links were saved as separate files, so the counts saved with them no
+        // longer hold
+        recount_links(&image.root);
+        let mut fs = SimpleFileSystem::with_backend(backend, SystemClock);
+        fs.total_bytes = AtomicUsize::new(usage_of(&image.root, &mut HashSet::new()));
+        fs.backend.set_root(image.root);
+        fs.cwd = image.cwd;
+        Ok(fs)
+    }
+}
+
+#[cfg(feature = "serde")]
+#[allow(dead_code)]
+impl SimpleFileSystem {
+    fn load_from_reader<R: io::Read>(reader: R) -> Result<Self, FileSystemError> {
+        Self::load_with_backend(reader, MemoryBackend::new(SystemClock.now()))
+    }
+}
+
+#[cfg(feature = "tar")]
+#[allow(dead_code)]
+impl<B: StorageBackend> SimpleFileSystem<B> {
+    // Unpack a tar archive below the working directory. Missing parent folders are
+    // created along the way and owner permission bits are mapped to Permissions.
+    fn import_tar<R: io::Read>(&mut self, reader: R) -> Result<(), FileSystemError> {
+        let mut archive = tar::Archive::new(reader);
+        // Folder modes are applied once everything is in, as tar does, so a read-only
+        // folder can still receive the entries that follow it
+        let mut folders = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let permissions = Permissions::from_mode(entry.header().mode()?);
+            if let Some((parent, _)) = path.trim_end_matches('/').rsplit_once('/') {
+                self.create_dir_all(parent, Permissions::ReadWrite)?;
+            }
+
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => {
+                    self.create_dir_all(&path, Permissions::ReadWrite)?;
+                    folders.push((path, permissions));
+                }
+                tar::EntryType::Regular => {
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data)?;
+                    self.write_new_file(&path, &data, permissions)?;
+                }
+                tar::EntryType::Symlink => {
+                    let target = entry.link_name()?.ok_or(FileSystemError::InvalidName)?;
+                    self.symlink(&target.to_string_lossy(), &path)?;
+                }
+                tar::EntryType::Link => {
+                    let existing = entry.link_name()?.ok_or(FileSystemError::InvalidName)?;
+                    self.link(&existing.to_string_lossy(), &path)?;
+                }
+                // Devices, fifos and the like have no counterpart here
+                _ => {}
+            }
+        }
+        for (path, permissions) in folders.into_iter().rev() {
+            self.chmod(&path, permissions)?;
+        }
+        Ok(())
+    }
+
+    // Write the whole tree as a tar archive, with paths relative to the root. Hard
+    // links are written once per name, as independent files.
+    fn export_tar<W: io::Write>(&self, writer: W) -> Result<(), FileSystemError> {
+        let mut builder = tar::Builder::new(writer);
+        for (path, metadata) in self.walk("/")? {
+            let name = path.trim_start_matches('/');
+            let node = self.backend.resolve(&self.split_path(&path), false)?;
+            let node = lock_node(&node)?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(metadata.permissions.to_mode(metadata.is_dir));
+            if let Ok(modified) = metadata.modified.duration_since(SystemTime::UNIX_EPOCH) {
+                header.set_mtime(modified.as_secs());
+            }
+            match &*node {
+                INode::Folder { .. } => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    builder.append_data(&mut header, name, io::empty())?;
+                }
+                file @ INode::File { .. } => {
+                    let data = file.file_contents()?;
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, name, &data[..])?;
+                }
+                INode::Symlink { target, .. } => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, name, target)?;
+                }
+            }
+        }
+        builder.into_inner()?;
+        Ok(())
+    }
+}
+
+impl<B: StorageBackend> FileSystem for SimpleFileSystem<B> {
+    fn create(
+        &mut self,
+        path: &str,
+        permissions_mode: Permissions,
+    ) -> Result<usize, FileSystemError> {
+        self.check_fd_limit()?;
+        if let Some((index, inner)) = self.mount_for(path) {
+            let inner_fd = self.mounts[index].fs.create(&inner, permissions_mode)?;
+            return Ok(self.adopt_mounted_fd(index, inner_fd));
+        }
+        let inode = self.insert_node(path, self.new_file_node(permissions_mode))?;
+        let components = self.split_path(path);
+        Ok(self.allocate_fd(inode, components, OpenFlags::READ | OpenFlags::WRITE))
+    }
+
+    fn mkdir(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError> {
+        if let Some((index, inner)) = self.mount_for(path) {
+            return self.mounts[index].fs.mkdir(&inner, permissions);
+        }
+        self.insert_node(
+            path,
+            INode::Folder {
+                contents: HashMap::new(),
+                permissions,
+                times: Timestamps::new(self.clock.now()),
+                ino: next_ino(),
+                xattrs: HashMap::new(),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FileSystemError> {
+        if let Some((index, inner)) = self.mount_for(path) {
+            return self.mounts[index].fs.unlink(&inner);
+        }
+        let node = self.remove_node(path, |node| match node {
+            INode::File { .. } | INode::Symlink { .. } => Ok(()),
+            INode::Folder { .. } => Err(FileSystemError::InvalidType),
+        })?;
+        lock_node(&node)?.drop_link();
+        self.release_if_orphaned(&node);
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FileSystemError> {
+        if let Some((index, inner)) = self.mount_for(path) {
+            return self.mounts[index].fs.rmdir(&inner);
+        }
+        // The root folder has no parent to be removed from
+        if self.split_path(path).is_empty() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        self.remove_node(path, |node| match node {
+            INode::Folder { contents, .. } if contents.is_empty() => Ok(()),
+            INode::Folder { .. } => Err(FileSystemError::DirectoryNotEmpty),
+            INode::File { .. } | INode::Symlink { .. } => Err(FileSystemError::InvalidType),
+        })?;
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Vec<String>, FileSystemError> {
+        if let Some((index, inner)) = self.mount_for(path) {
+            return self.mounts[index].fs.list_dir(&inner);
+        }
+        let components = self.split_path(path);
+        // Sort so callers get the same order on every listing
+        let mut names = self.backend.list(&components)?;
+        names.sort();
+        Ok(names)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        match (self.mount_for(from), self.mount_for(to)) {
+            (None, None) => {}
+            (Some((index, inner_from)), Some((to_index, inner_to))) if index == to_index => {
+                return self.mounts[index].fs.rename(&inner_from, &inner_to);
+            }
+            _ => return Err(FileSystemError::CrossDevice),
+        }
+        self.check_writable()?;
+        let from_components = self.split_path(from);
+        let mut to_components = self.split_path(to);
+        if from_components.is_empty() || to_components.is_empty() {
+            return Err(FileSystemError::InvalidType);
+        }
+        self.validate_name(&to_components)?;
+
+        let node = self.backend.resolve(&from_components, false)?;
+        let node_is_folder = matches!(&*lock_node(&node)?, INode::Folder { .. });
+        // The destination's parent is found before anything moves, and named by its real
+        // path, so taking the source out can't change where the destination is
+        let name = to_components.pop().unwrap_or_default();
+        let to_parent = self.lookup(&to_components)?;
+        if !matches!(&*lock_node(&to_parent)?, INode::Folder { .. }) {
+            return Err(FileSystemError::InvalidType);
+        }
+        // A folder moved below itself would be cut off from the tree along with its new
+        // parent, however that parent is reached
+        if node_is_folder && (Arc::ptr_eq(&to_parent, &node) || holds_node(&node, &to_parent)) {
+            return Err(FileSystemError::InvalidMove);
+        }
+        if node_is_folder && self.holds_mount(&node) {
+            return Err(FileSystemError::CrossDevice);
+        }
+        let mut to_components = self.split_path(&self.canonicalize(&path_of(&to_components))?);
+        to_components.push(name);
+        self.check_parent_writable(&from_components)?;
+        self.check_parent_writable(&to_components)?;
+
+        // Only a file may replace a file, and only an empty folder may be replaced by a folder
+        let mut existing = match self.backend.resolve(&to_components, false) {
+            Ok(existing) => Some(existing),
+            Err(FileSystemError::FileNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if existing
+            .as_ref()
+            .is_some_and(|existing| Arc::ptr_eq(existing, &node))
+        {
+            // Two names for one file are both left in place, but a change of case alone
+            // renames the entry itself, with nothing to replace
+            if !self.is_case_change(&from_components, &to_components)? {
+                return Ok(());
+            }
+            existing = None;
+        }
+        if let Some(existing) = &existing {
+            if self.holds_mount(existing) {
+                return Err(FileSystemError::CrossDevice);
+            }
+            match &*lock_node(existing)? {
+                INode::Folder { contents, .. } if !contents.is_empty() => {
+                    return Err(FileSystemError::DirectoryNotEmpty)
+                }
+                INode::Folder { .. } if !node_is_folder => {
+                    return Err(FileSystemError::InvalidType)
+                }
+                INode::File { .. } | INode::Symlink { .. } if node_is_folder => {
+                    return Err(FileSystemError::InvalidType)
+                }
+                _ => {}
+            }
+        }
+
+        self.backend.remove(&from_components, &|_| Ok(()))?;
+        self.layout = next_layout();
+        if existing.is_some() {
+            if let Err(e) = self.backend.remove(&to_components, &|_| Ok(())) {
+                let _ = self.backend.insert(&from_components, node);
+                return Err(e);
+            }
+        }
+        // The handle itself moves to the new name, so descriptors opened under the old
+        // one keep reading and writing the same file; only the paths they report change
+        if let Err(e) = self.backend.insert(&to_components, node.clone()) {
+            // Put everything back, so a failed rename loses nothing
+            if let Some(replaced) = existing {
+                let _ = self.backend.insert(&to_components, replaced);
+            }
+            let _ = self.backend.insert(&from_components, node);
+            return Err(e);
+        }
+        if let Some(replaced) = existing {
+            lock_node(&replaced)?.drop_link();
+            self.release_if_orphaned(&replaced);
+        }
+
+        for desc in self.file_descriptors.values_mut() {
+            if desc.path.starts_with(&from_components) {
+                desc.path
+                    .splice(..from_components.len(), to_components.iter().cloned());
+            }
+        }
+        self.emit(FsEvent::Renamed {
+            from: path_of(&from_components),
+            to: path_of(&to_components),
+        });
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, FileSystemError> {
+        if let Some((index, inner)) = self.mount_for(path) {
+            return self.mounts[index].fs.stat(&inner);
+        }
+        let components = self.split_path(path);
+        let node = self.lookup(&components)?;
+        let metadata = lock_node(&node)?.metadata();
+        Ok(metadata)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        if let Some((index, inner)) = self.mount_for(path) {
+            return self.mounts[index].fs.exists(&inner);
+        }
+        let components = self.split_path(path);
+        self.backend.resolve(&components, false).is_ok()
+    }
+
+    fn chmod(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError> {
+        if let Some((index, inner)) = self.mount_for(path) {
+            return self.mounts[index].fs.chmod(&inner, permissions);
+        }
+        self.check_writable()?;
+        let components = self.split_path(path);
+        let node = self.lookup(&components)?;
+        match &mut *lock_node(&node)? {
+            INode::Folder {
+                permissions: current,
+                ..
+            }
+            | INode::File {
+                permissions: current,
+                ..
+            }
+            | INode::Symlink {
+                permissions: current,
+                ..
+            } => *current = permissions,
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, path: &str) -> Result<usize, FileSystemError> {
+        self.open_with(path, OpenFlags::READ | OpenFlags::WRITE)
+    }
+
+    fn open_with(&mut self, path: &str, flags: OpenFlags) -> Result<usize, FileSystemError> {
+        self.check_fd_limit()?;
+        if let Some((index, inner)) = self.mount_for(path) {
+            let inner_fd = self.mounts[index].fs.open_with(&inner, flags)?;
+            return Ok(self.adopt_mounted_fd(index, inner_fd));
+        }
+        // Descriptors may still be opened for writing; the writes themselves fail
+        if flags.contains(OpenFlags::TRUNC) {
+            self.check_writable()?;
+        }
+        let exclusive = flags.contains(OpenFlags::CREATE | OpenFlags::EXCL);
+        // Folders can be opened too, for readdir; reads and writes on them fail
+        let inode = match self.lookup(&self.split_path(path)) {
+            Ok(_) if exclusive => return Err(FileSystemError::FileExists),
+            Ok(inode) => inode,
+            Err(FileSystemError::FileNotFound) if flags.contains(OpenFlags::CREATE) => {
+                self.insert_node(path, self.new_file_node(Permissions::ReadWrite))?
+            }
+            Err(e) => return Err(e),
+        };
+        // Descriptors always work on plain bytes, so positions stay in file offsets
+        #[cfg(feature = "compression")]
+        lock_node(&inode)?.inflate()?;
+
+        let is_folder = matches!(&*lock_node(&inode)?, INode::Folder { .. });
+        if is_folder && flags.contains(OpenFlags::TRUNC) {
+            return Err(FileSystemError::InvalidType);
+        }
+        if flags.contains(OpenFlags::TRUNC) {
+            if let INode::File {
+                data,
+                permissions,
+                times,
+                history,
+                ..
+            } = &mut *lock_node(&inode)?
+            {
+                if !flags.contains(OpenFlags::WRITE) || !permissions.can_write() {
+                    return Err(FileSystemError::PermissionDenied);
+                }
+                self.keep_version(history, data, times.modified);
+                self.release_bytes(data.len());
+                *data = Arc::default();
+                times.modified = self.clock.now();
+                self.emit(FsEvent::Written(path_of(&self.split_path(path))));
+            }
+        }
+
+        let components = self.split_path(path);
+        Ok(self.allocate_fd(inode, components, flags))
+    }
+
+    fn close(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        if let Some((index, inner_fd)) = self.mounted_fds.remove(&fd) {
+            self.free_fds.push(Reverse(fd));
+            return self.mounts[index].fs.close(inner_fd);
+        }
+        let file_desc = self
+            .file_descriptors
+            .remove(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        self.free_fds.push(Reverse(fd));
+        self.let_go(file_desc);
+        Ok(())
+    }
+
+    fn write(&mut self, fd: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        if let Some(&(index, inner_fd)) = self.mounted_fds.get(&fd) {
+            return self.mounts[index].fs.write(inner_fd, data);
+        }
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        // Append mode always lands at the end, whatever the position is
+        let start = if file_desc.flags.contains(OpenFlags::APPEND) {
+            self.fstat(fd)?.size
+        } else {
+            file_desc.position
+        };
+
+        self.write_at(fd, start, data)?;
+        self.file_descriptors.get_mut(&fd).unwrap().position = start + data.len();
+        Ok(())
+    }
+
+    fn read(&mut self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        if let Some(&(index, inner_fd)) = self.mounted_fds.get(&fd) {
+            return self.mounts[index].fs.read(inner_fd, buffer);
+        }
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        let bytes_read = self.read_at(fd, file_desc.position, buffer)?;
+        self.file_descriptors.get_mut(&fd).unwrap().position += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn seek(&mut self, fd: usize, offset: OffsetFrom) -> Result<usize, FileSystemError> {
+        if let Some(&(index, inner_fd)) = self.mounted_fds.get(&fd) {
+            return self.mounts[index].fs.seek(inner_fd, offset);
+        }
+        let file_desc = self
+            .file_descriptors
+            .get_mut(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+
+        let inode = lock_node(&file_desc.inode)?;
+        let file_size = if let INode::File { data, .. } = &*inode {
+            data.len()
+        } else {
+            return Err(FileSystemError::InvalidType);
+        };
+
+        let new_position = match offset {
+            OffsetFrom::Start(pos) => pos,
+            OffsetFrom::Current(offset) => {
+                if let Some(pos) = file_desc.position.checked_add_signed(offset) {
+                    pos
+                } else {
+                    return Err(FileSystemError::InvalidSeek);
+                }
+            }
+            OffsetFrom::End(offset) => {
+                if let Some(pos) = file_size.checked_add_signed(offset) {
+                    pos
+                } else {
+                    return Err(FileSystemError::InvalidSeek);
+                }
+            }
+        };
+
+        // Seeking past the end is allowed; a later write fills the gap with zeros
+        file_desc.position = new_position;
+        Ok(file_desc.position)
+    }
+
+    fn tell(&self, fd: usize) -> Result<usize, FileSystemError> {
+        if let Some(&(index, inner_fd)) = self.mounted_fds.get(&fd) {
+            return self.mounts[index].fs.tell(inner_fd);
+        }
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        Ok(file_desc.position)
+    }
+
+    fn ftruncate(&mut self, fd: usize, len: usize) -> Result<(), FileSystemError> {
+        if let Some(&(index, inner_fd)) = self.mounted_fds.get(&fd) {
+            return self.mounts[index].fs.ftruncate(inner_fd, len);
+        }
+        let file_desc = self
+            .file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        self.check_writable()?;
+        let inode = file_desc.inode.clone();
+
+        match &mut *lock_node(&inode)? {
+            INode::File {
+                data,
+                permissions,
+                times,
+                history,
+                ..
+            } => {
+                if !file_desc.flags.contains(OpenFlags::WRITE) || !permissions.can_write() {
+                    return Err(FileSystemError::PermissionDenied);
+                }
+                if len > data.len() {
+                    self.check_file_size(len)?;
+                    self.reserve_bytes(len - data.len())?;
+                } else {
+                    self.release_bytes(data.len() - len);
+                }
+                self.keep_version(history, data, times.modified);
+                Arc::make_mut(data).resize(len, 0);
+                self.share_data(data);
+                times.modified = self.clock.now();
+                self.emit(FsEvent::Written(path_of(&file_desc.path)));
+            }
+            _ => return Err(FileSystemError::InvalidType),
+        }
+
+        // Keep every descriptor on this file within its new bounds
+        for desc in self.file_descriptors.values_mut() {
+            if Arc::ptr_eq(&desc.inode, &inode) {
+                desc.position = desc.position.min(len);
+            }
+        }
+        Ok(())
+    }
+
+    fn fstat(&self, fd: usize) -> Result<Metadata, FileSystemError> {
+        if let Some(&(index, inner_fd)) = self.mounted_fds.get(&fd) {
+            return self.mounts[index].fs.fstat(inner_fd);
+        }
+        let inode = self.get_file_descriptor(fd)?;
+        let metadata = lock_node(&inode)?.metadata();
+        Ok(metadata)
+    }
+
+    // Writes land in memory straight away, so only mounts can have anything to flush
+    fn flush(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        if let Some(&(index, inner_fd)) = self.mounted_fds.get(&fd) {
+            return self.mounts[index].fs.flush(inner_fd);
+        }
+        self.get_file_descriptor(fd)?;
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<(), FileSystemError> {
+        self.mounts
+            .iter_mut()
+            .try_for_each(|mount| mount.fs.sync_all())
+    }
+}
+
+// Handles tying an open descriptor to its file system for std::io interop. They read,
+// write and seek through the descriptor itself, so its position is always theirs.
+#[allow(dead_code)]
+struct FileReader<'a, B: StorageBackend = MemoryBackend> {
+    fs: &'a mut SimpleFileSystem<B>,
+    fd: usize,
+}
+
+#[allow(dead_code)]
+struct FileWriter<'a, B: StorageBackend = MemoryBackend> {
+    fs: &'a mut SimpleFileSystem<B>,
+    fd: usize,
+}
+
+impl<B: StorageBackend> io::Read for FileReader<'_, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.fs.read(self.fd, buf)?)
+    }
+}
+
+impl<B: StorageBackend> io::Seek for FileReader<'_, B> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        seek_descriptor(self.fs, self.fd, pos)
+    }
+}
+
+impl<B: StorageBackend> io::Write for FileWriter<'_, B> {
+    // write already lands at the end in append mode
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.fs.write(self.fd, buf)?;
+        Ok(buf.len())
+    }
+
+    // Writes land in the tree straight away, so there is nothing to push
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<B: StorageBackend> io::Seek for FileWriter<'_, B> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        seek_descriptor(self.fs, self.fd, pos)
+    }
+}
+
+#[allow(dead_code)]
+fn seek_descriptor<B: StorageBackend>(
+    fs: &mut SimpleFileSystem<B>,
+    fd: usize,
+    pos: io::SeekFrom,
+) -> io::Result<u64> {
+    let offset = match pos {
+        io::SeekFrom::Start(pos) => OffsetFrom::Start(pos as usize),
+        io::SeekFrom::Current(offset) => OffsetFrom::Current(offset as isize),
+        io::SeekFrom::End(offset) => OffsetFrom::End(offset as isize),
+    };
+    Ok(fs.seek(fd, offset)? as u64)
+}
+
+// Writer that collects small writes and hands them to the descriptor in batches of up
+// to `capacity` bytes. Whatever is still buffered is written on flush or drop; errors
+// on drop are lost, so call flush to see them.
+#[allow(dead_code)]
+struct BufferedWriter<'a, B: StorageBackend = MemoryBackend> {
+    fs: &'a mut SimpleFileSystem<B>,
+    fd: usize,
+    buffer: Vec<u8>,
+    capacity: usize,
+}
+
+#[allow(dead_code)]
+impl<B: StorageBackend> BufferedWriter<'_, B> {
+    fn write_buffer(&mut self) -> Result<(), FileSystemError> {
+        if !self.buffer.is_empty() {
+            self.fs.write(self.fd, &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<B: StorageBackend> io::Write for BufferedWriter<'_, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.capacity {
+            self.write_buffer()?;
+        }
+        // Writes too big to buffer go straight through
+        if buf.len() >= self.capacity {
+            self.fs.write(self.fd, buf)?;
+        } else {
+            self.buffer.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        self.fs.flush(self.fd)?;
+        Ok(())
+    }
+}
+
+impl<B: StorageBackend> Drop for BufferedWriter<'_, B> {
+    fn drop(&mut self) {
+        let _ = self.write_buffer();
+    }
+}
+
+// Descriptor on a host file. The position is tracked here rather than in the host
+// file's own cursor so reads leave it alone, matching SimpleFileSystem.
+#[allow(dead_code)]
+struct HostDescriptor {
+    file: fs::File,
+    path: PathBuf,
+    position: u64,
+    flags: OpenFlags,
+}
+
+// FileSystem backed by a directory on the host, so data outlives the process. Paths
+// are resolved lexically below `root` and may never name anything outside it.
+#[allow(dead_code)]
+struct PersistentFileSystem {
+    root: PathBuf,
+    file_descriptors: HashMap<usize, HostDescriptor>,
+    next_fd: usize,
+}
+
+#[allow(dead_code)]
+impl PersistentFileSystem {
+    fn new(root: impl AsRef<Path>) -> Result<Self, FileSystemError> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(FileSystemError::InvalidType);
+        }
+        Ok(PersistentFileSystem {
+            root,
+            file_descriptors: HashMap::new(),
+            next_fd: 1,
+        })
+    }
+
+    fn host_path(&self, path: &str) -> Result<PathBuf, FileSystemError> {
+        let host = join_path(Vec::new(), path)
+            .iter()
+            .fold(self.root.clone(), |host, component| host.join(component));
+
+        // ".." cannot climb out lexically, but a symlink on the host still could
+        if let Some(existing) = host.ancestors().find(|p| p.exists()) {
+            if !fs::canonicalize(existing)?.starts_with(&self.root) {
+                return Err(FileSystemError::PermissionDenied);
+            }
+        }
+        Ok(host)
+    }
+
+    fn descriptor(&self, fd: usize) -> Result<&HostDescriptor, FileSystemError> {
+        self.file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)
+    }
+
+    fn allocate_fd(&mut self, file: fs::File, path: PathBuf, flags: OpenFlags) -> usize {
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.file_descriptors.insert(
+            fd,
+            HostDescriptor {
+                file,
+                path,
+                position: 0,
+                flags,
+            },
+        );
+        fd
+    }
+
+    fn metadata_of(host: &Path, meta: &fs::Metadata) -> Result<Metadata, FileSystemError> {
+        let size = if meta.is_dir() {
+            fs::read_dir(host)?.count()
+        } else {
+            meta.len() as usize
+        };
+        let modified = meta.modified()?;
+        Ok(Metadata {
+            is_dir: meta.is_dir(),
+            size,
+            permissions: permissions_from_host(meta),
+            links: host_link_count(meta),
+            ino: host_ino(meta),
+            created: meta.created().unwrap_or(modified),
+            modified,
+            accessed: meta.accessed().unwrap_or(modified),
+        })
+    }
+}
+
+#[cfg(unix)]
+#[allow(dead_code)]
+fn set_host_permissions(
+    host: &Path,
+    permissions: &Permissions,
+    is_dir: bool,
+) -> Result<(), FileSystemError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = permissions.to_mode(is_dir);
+    fs::set_permissions(host, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_host_permissions(
+    host: &Path,
+    permissions: &Permissions,
+    _is_dir: bool,
+) -> Result<(), FileSystemError> {
+    let mut host_permissions = fs::metadata(host)?.permissions();
+    host_permissions.set_readonly(!permissions.can_write());
+    fs::set_permissions(host, host_permissions)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[allow(dead_code)]
+fn create_host_symlink(target: &str, host: &Path) -> Result<(), FileSystemError> {
+    std::os::unix::fs::symlink(target, host)?;
+    Ok(())
+}
+
+// Other hosts need to know whether a symlink points at a folder, so it is skipped there
+#[cfg(not(unix))]
+fn create_host_symlink(_target: &str, _host: &Path) -> Result<(), FileSystemError> {
+    Ok(())
+}
+
+#[cfg(unix)]
+#[allow(dead_code)]
+fn permissions_from_host(meta: &fs::Metadata) -> Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    Permissions::from_mode(meta.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn permissions_from_host(meta: &fs::Metadata) -> Permissions {
+    if meta.permissions().readonly() {
+        Permissions::Read
+    } else {
+        Permissions::ReadWrite
+    }
+}
+
+#[cfg(unix)]
+#[allow(dead_code)]
+fn host_link_count(meta: &fs::Metadata) -> usize {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() as usize
+}
+
+#[cfg(not(unix))]
+fn host_link_count(_meta: &fs::Metadata) -> usize {
+    1
+}
+
+#[cfg(unix)]
+#[allow(dead_code)]
+fn host_ino(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+#[cfg(not(unix))]
+fn host_ino(_meta: &fs::Metadata) -> u64 {
+    0
+}
+
+impl FileSystem for PersistentFileSystem {
+    fn create(
+        &mut self,
+        path: &str,
+        permissions_mode: Permissions,
+    ) -> Result<usize, FileSystemError> {
+        let host = self.host_path(path)?;
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&host)?;
+        set_host_permissions(&host, &permissions_mode, false)?;
+        Ok(self.allocate_fd(file, host, OpenFlags::READ | OpenFlags::WRITE))
+    }
+
+    fn mkdir(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError> {
+        let host = self.host_path(path)?;
+        fs::create_dir(&host)?;
+        set_host_permissions(&host, &permissions, true)?;
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let host = self.host_path(path)?;
+        if fs::symlink_metadata(&host)?.is_dir() {
+            return Err(FileSystemError::InvalidType);
+        }
+        fs::remove_file(&host)?;
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let host = self.host_path(path)?;
+        if host == self.root {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        if !fs::symlink_metadata(&host)?.is_dir() {
+            return Err(FileSystemError::InvalidType);
+        }
+        fs::remove_dir(&host)?;
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Vec<String>, FileSystemError> {
+        let host = self.host_path(path)?;
+        if !fs::metadata(&host)?.is_dir() {
+            return Err(FileSystemError::InvalidType);
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&host)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let from = self.host_path(from)?;
+        let to = self.host_path(to)?;
+        if to != from && to.starts_with(&from) && fs::metadata(&from)?.is_dir() {
+            return Err(FileSystemError::InvalidMove);
+        }
+        fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, FileSystemError> {
+        let host = self.host_path(path)?;
+        let meta = fs::metadata(&host)?;
+        Self::metadata_of(&host, &meta)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.host_path(path)
+            .is_ok_and(|host| fs::symlink_metadata(host).is_ok())
+    }
+
+    fn chmod(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError> {
+        let host = self.host_path(path)?;
+        let is_dir = fs::metadata(&host)?.is_dir();
+        set_host_permissions(&host, &permissions, is_dir)?;
+        Ok(())
+    }
+
+    fn open(&mut self, path: &str) -> Result<usize, FileSystemError> {
+        self.open_with(path, OpenFlags::READ | OpenFlags::WRITE)
+    }
+
+    fn open_with(&mut self, path: &str, flags: OpenFlags) -> Result<usize, FileSystemError> {
+        let host = self.host_path(path)?;
+        match fs::metadata(&host) {
+            Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCL) => {
+                return Err(FileSystemError::FileExists)
+            }
+            Ok(meta) if meta.is_dir() => return Err(FileSystemError::InvalidType),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound && flags.contains(OpenFlags::CREATE) => {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&host)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let writable = flags.contains(OpenFlags::WRITE);
+        let file = fs::OpenOptions::new()
+            .read(flags.contains(OpenFlags::READ) || !writable)
+            .write(writable)
+            .open(&host)?;
+
+        if flags.contains(OpenFlags::TRUNC) {
+            if !writable || !permissions_from_host(&file.metadata()?).can_write() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+            file.set_len(0)?;
+        }
+
+        Ok(self.allocate_fd(file, host, flags))
+    }
+
+    fn close(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        if self.file_descriptors.remove(&fd).is_some() {
+            Ok(())
+        } else {
+            Err(FileSystemError::InvalidFileDescriptor)
+        }
+    }
+
+    fn write(&mut self, fd: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        let file_desc = self
+            .file_descriptors
+            .get_mut(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        let meta = file_desc.file.metadata()?;
+        if !file_desc.flags.contains(OpenFlags::WRITE) || !permissions_from_host(&meta).can_write()
+        {
+            return Err(FileSystemError::PermissionDenied);
+        }
+
+        let start = if file_desc.flags.contains(OpenFlags::APPEND) {
+            meta.len()
+        } else {
+            file_desc.position
+        };
+        // Writing past the end leaves a hole that the host fills with zeros
+        file_desc.file.seek(io::SeekFrom::Start(start))?;
+        file_desc.file.write_all(data)?;
+        file_desc.position = start + data.len() as u64;
+        Ok(())
+    }
+
+    fn read(&mut self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        let file_desc = self
+            .file_descriptors
+            .get_mut(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        if !file_desc.flags.contains(OpenFlags::READ)
+            || !permissions_from_host(&file_desc.file.metadata()?).can_read()
+        {
+            return Err(FileSystemError::PermissionDenied);
+        }
+
+        let mut file = &file_desc.file;
+        file.seek(io::SeekFrom::Start(file_desc.position))?;
+        let mut bytes_read = 0;
+        while bytes_read < buffer.len() {
+            match file.read(&mut buffer[bytes_read..])? {
+                0 => break,
+                n => bytes_read += n,
+            }
+        }
+        file_desc.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+
+    fn seek(&mut self, fd: usize, offset: OffsetFrom) -> Result<usize, FileSystemError> {
+        let file_desc = self
+            .file_descriptors
+            .get_mut(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)?;
+        let file_size = file_desc.file.metadata()?.len() as usize;
+
+        let new_position = match offset {
+            OffsetFrom::Start(pos) => Some(pos),
+            OffsetFrom::Current(offset) => (file_desc.position as usize).checked_add_signed(offset),
+            OffsetFrom::End(offset) => file_size.checked_add_signed(offset),
+        }
+        .ok_or(FileSystemError::InvalidSeek)?;
+
+        file_desc.position = new_position as u64;
+        Ok(new_position)
+    }
+
+    fn tell(&self, fd: usize) -> Result<usize, FileSystemError> {
+        Ok(self.descriptor(fd)?.position as usize)
+    }
+
+    fn ftruncate(&mut self, fd: usize, len: usize) -> Result<(), FileSystemError> {
+        let file_desc = self.descriptor(fd)?;
+        if !file_desc.flags.contains(OpenFlags::WRITE)
+            || !permissions_from_host(&file_desc.file.metadata()?).can_write()
+        {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        file_desc.file.set_len(len as u64)?;
+
+        let path = file_desc.path.clone();
+        for desc in self.file_descriptors.values_mut() {
+            if desc.path == path {
+                desc.position = desc.position.min(len as u64);
+            }
+        }
+        Ok(())
+    }
+
+    fn fstat(&self, fd: usize) -> Result<Metadata, FileSystemError> {
+        let file_desc = self.descriptor(fd)?;
+        let meta = file_desc.file.metadata()?;
+        Self::metadata_of(&file_desc.path, &meta)
+    }
+
+    fn flush(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        self.descriptor(fd)?.file.sync_data()?;
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<(), FileSystemError> {
+        for desc in self.file_descriptors.values() {
+            desc.file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+// Marker files the overlay keeps in its upper layer. A file named with the prefix
+// followed by an entry's name hides that entry of the lower layer, and a folder
+// holding the opaque marker hides everything in the lower folder at the same path.
+#[allow(dead_code)]
+const WHITEOUT_PREFIX: &str = ".wh.";
+#[allow(dead_code)]
+const OPAQUE_MARKER: &str = ".wh..opq";
+
+// Which layer of an OverlayFs an open descriptor points into
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+enum Layer {
+    Upper,
+    Lower,
+}
+
+#[allow(dead_code)]
+struct OverlayDescriptor {
+    layer: Layer,
+    // The descriptor's number in that layer
+    fd: usize,
+    path: Vec<String>,
+    flags: OpenFlags,
+}
+
+// A merged view of two file systems, as container overlays do. Everything is read
+// from the upper layer when it has the path and from the lower one otherwise; every
+// change goes to the upper layer, so the lower one is never written. A lower file is
+// copied up before its first change, and entries removed from the lower layer stay
+// hidden behind whiteout markers. Descriptors already reading a lower file keep
+// seeing the lower copy after another one copies it up.
+#[allow(dead_code)]
+struct OverlayFs {
+    lower: Box<dyn FileSystem + Send + Sync>,
+    upper: Box<dyn FileSystem + Send + Sync>,
+    file_descriptors: HashMap<usize, OverlayDescriptor>,
+    next_fd: usize,
+}
+
+#[allow(dead_code)]
+impl OverlayFs {
+    fn new(
+        lower: Box<dyn FileSystem + Send + Sync>,
+        upper: Box<dyn FileSystem + Send + Sync>,
+    ) -> Self {
+        OverlayFs {
+            lower,
+            upper,
+            file_descriptors: HashMap::new(),
+            next_fd: 1,
+        }
+    }
+
+    // Normalized components of `path`. Names with the whiteout prefix are the
+    // overlay's own and cannot be used through it.
+    fn split(path: &str) -> Result<Vec<String>, FileSystemError> {
+        let components = join_path(Vec::new(), path);
+        if components
+            .iter()
+            .any(|name| name.starts_with(WHITEOUT_PREFIX))
+        {
+            return Err(FileSystemError::InvalidName);
+        }
+        Ok(components)
+    }
+
+    // Path of the marker hiding the lower entry at `components`, which is not the root
+    fn whiteout_of(components: &[String]) -> String {
+        let (name, parent) = components.split_last().unwrap();
+        let mut marker = parent.to_vec();
+        marker.push(format!("{}{}", WHITEOUT_PREFIX, name));
+        path_of(&marker)
+    }
+
+    fn opaque_marker_of(components: &[String]) -> String {
+        let mut marker = components.to_vec();
+        marker.push(OPAQUE_MARKER.to_string());
+        path_of(&marker)
+    }
+
+    // Whether the upper layer hides the lower entry at `components`: the entry or a
+    // folder above it has a whiteout, a folder above it is opaque, or something above
+    // it is a file in the upper layer
+    fn masked(&self, components: &[String]) -> bool {
+        (1..=components.len()).any(|depth| {
+            let prefix = &components[..depth];
+            let above = depth < components.len();
+            self.upper.exists(&Self::whiteout_of(prefix))
+                || (above && self.upper.exists(&Self::opaque_marker_of(prefix)))
+                || (above && self.upper.is_file(&path_of(prefix)))
+        })
+    }
+
+    fn in_upper(&self, components: &[String]) -> bool {
+        self.upper.exists(&path_of(components))
+    }
+
+    fn in_lower(&self, components: &[String]) -> bool {
+        !self.masked(components) && self.lower.exists(&path_of(components))
+    }
+
+    fn merged_stat(&self, components: &[String]) -> Result<Metadata, FileSystemError> {
+        let path = path_of(components);
+        if self.in_upper(components) {
+            self.upper.stat(&path)
+        } else if self.in_lower(components) {
+            self.lower.stat(&path)
+        } else {
+            Err(FileSystemError::FileNotFound)
+        }
+    }
+
+    // Bring the entry at `components` into the upper layer, along with the folders
+    // above it. Folders are made empty there, since their lower contents still show
+    // through; files are copied whole with their permissions.
+    fn copy_up(&mut self, components: &[String]) -> Result<(), FileSystemError> {
+        if self.in_upper(components) {
+            return Ok(());
+        }
+        let metadata = self.merged_stat(components)?;
+        self.copy_up_parents(components)?;
+        let path = path_of(components);
+        if metadata.is_dir {
+            return self.upper.mkdir(&path, metadata.permissions);
+        }
+
+        let fd = self.lower.open_with(&path, OpenFlags::READ)?;
+        let mut contents = vec![0; metadata.size];
+        let read = self.lower.read(fd, &mut contents);
+        self.lower.close(fd)?;
+        contents.truncate(read?);
+
+        let fd = self.upper.create(&path, Permissions::ReadWrite)?;
+        let written = self.upper.write(fd, &contents);
+        self.upper.close(fd)?;
+        written?;
+        self.upper.chmod(&path, metadata.permissions)
+    }
+
+    fn copy_up_parents(&mut self, components: &[String]) -> Result<(), FileSystemError> {
+        for depth in 1..components.len() {
+            let parent = &components[..depth];
+            if !self.merged_stat(parent)?.is_dir {
+                return Err(FileSystemError::InvalidType);
+            }
+            self.copy_up(parent)?;
+        }
+        Ok(())
+    }
+
+    // Get the upper layer ready for a new entry at `components`, dropping a whiteout
+    // left there by an earlier removal. Returns whether there was one.
+    fn prepare_new(&mut self, components: &[String]) -> Result<bool, FileSystemError> {
+        self.copy_up_parents(components)?;
+        let whiteout = Self::whiteout_of(components);
+        if !self.upper.exists(&whiteout) {
+            return Ok(false);
+        }
+        self.upper.unlink(&whiteout)?;
+        Ok(true)
+    }
+
+    // Hide the lower entry at `components`, or the lower contents of the upper folder
+    // there with `opaque`
+    fn write_marker(&mut self, components: &[String], opaque: bool) -> Result<(), FileSystemError> {
+        self.copy_up_parents(components)?;
+        let marker = if opaque {
+            Self::opaque_marker_of(components)
+        } else {
+            Self::whiteout_of(components)
+        };
+        let fd = self.upper.create(&marker, Permissions::Read)?;
+        self.upper.close(fd)
+    }
+
+    fn allocate_fd(
+        &mut self,
+        layer: Layer,
+        fd: usize,
+        path: Vec<String>,
+        flags: OpenFlags,
+    ) -> usize {
+        let overlay_fd = self.next_fd;
+        self.next_fd += 1;
+        self.file_descriptors.insert(
+            overlay_fd,
+            OverlayDescriptor {
+                layer,
+                fd,
+                path,
+                flags,
+            },
+        );
+        overlay_fd
+    }
+
+    fn descriptor(&self, fd: usize) -> Result<&OverlayDescriptor, FileSystemError> {
+        self.file_descriptors
+            .get(&fd)
+            .ok_or(FileSystemError::InvalidFileDescriptor)
+    }
+
+    // The layer a descriptor points into and its number there
+    fn inner(&self, fd: usize) -> Result<(&dyn FileSystem, usize), FileSystemError> {
+        let desc = self.descriptor(fd)?;
+        match desc.layer {
+            Layer::Upper => Ok((&*self.upper, desc.fd)),
+            Layer::Lower => Ok((&*self.lower, desc.fd)),
+        }
+    }
+
+    fn inner_mut(&mut self, fd: usize) -> Result<(&mut dyn FileSystem, usize), FileSystemError> {
+        let desc = self.descriptor(fd)?;
+        let inner_fd = desc.fd;
+        match desc.layer {
+            Layer::Upper => Ok((&mut *self.upper, inner_fd)),
+            Layer::Lower => Ok((&mut *self.lower, inner_fd)),
+        }
+    }
+
+    // Before a change through a descriptor on a lower file, copy the file up and move
+    // the descriptor onto the copy at the same position
+    fn copy_up_descriptor(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        let desc = self.descriptor(fd)?;
+        if desc.layer == Layer::Upper {
+            return Ok(());
+        }
+        if !desc.flags.contains(OpenFlags::WRITE) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let (lower_fd, path, flags) = (desc.fd, desc.path.clone(), desc.flags);
+        let position = self.lower.tell(lower_fd)?;
+        self.copy_up(&path)?;
+
+        // The file exists in the upper layer by now, so an exclusive create would fail
+        let flags = OpenFlags(flags.0 & !OpenFlags::EXCL.0);
+        let upper_fd = self.upper.open_with(&path_of(&path), flags)?;
+        self.upper.seek(upper_fd, OffsetFrom::Start(position))?;
+        self.lower.close(lower_fd)?;
+        let desc = self.file_descriptors.get_mut(&fd).unwrap();
+        desc.layer = Layer::Upper;
+        desc.fd = upper_fd;
+        Ok(())
+    }
+}
+
+impl FileSystem for OverlayFs {
+    fn create(
+        &mut self,
+        path: &str,
+        permissions_mode: Permissions,
+    ) -> Result<usize, FileSystemError> {
+        let components = Self::split(path)?;
+        if self.in_upper(&components) || self.in_lower(&components) {
+            return Err(FileSystemError::FileExists);
+        }
+        self.prepare_new(&components)?;
+        let fd = self.upper.create(&path_of(&components), permissions_mode)?;
+        Ok(self.allocate_fd(
+            Layer::Upper,
+            fd,
+            components,
+            OpenFlags::READ | OpenFlags::WRITE,
+        ))
+    }
+
+    fn mkdir(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError> {
+        let components = Self::split(path)?;
+        if self.in_upper(&components) || self.in_lower(&components) {
+            return Err(FileSystemError::FileExists);
+        }
+        let replaces_removed = self.prepare_new(&components)?;
+        self.upper.mkdir(&path_of(&components), permissions)?;
+        // A removed lower folder at this path would otherwise show its old contents
+        if replaces_removed {
+            self.write_marker(&components, true)?;
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let components = Self::split(path)?;
+        if self.merged_stat(&components)?.is_dir {
+            return Err(FileSystemError::InvalidType);
+        }
+        let in_lower = self.in_lower(&components);
+        if self.in_upper(&components) {
+            self.upper.unlink(&path_of(&components))?;
+        }
+        if in_lower {
+            self.write_marker(&components, false)?;
+        }
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let components = Self::split(path)?;
+        if components.is_empty() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        if !self.merged_stat(&components)?.is_dir {
+            return Err(FileSystemError::InvalidType);
+        }
+        if !self.list_dir(path)?.is_empty() {
+            return Err(FileSystemError::DirectoryNotEmpty);
+        }
+        let in_lower = self.in_lower(&components);
+        if self.in_upper(&components) {
+            // Nothing is visible inside, so whatever the upper folder holds is markers
+            let path = path_of(&components);
+            for marker in self.upper.list_dir(&path)? {
+                self.upper.unlink(&format!("{}/{}", path, marker))?;
+            }
+            self.upper.rmdir(&path)?;
+        }
+        if in_lower {
+            self.write_marker(&components, false)?;
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Vec<String>, FileSystemError> {
+        let components = Self::split(path)?;
+        if !self.merged_stat(&components)?.is_dir {
+            return Err(FileSystemError::InvalidType);
+        }
+        let path = path_of(&components);
+        let mut names = Vec::new();
+        let mut opaque = false;
+        if self.in_upper(&components) {
+            opaque = self.upper.exists(&Self::opaque_marker_of(&components));
+            names.extend(
+                self.upper
+                    .list_dir(&path)?
+                    .into_iter()
+                    .filter(|name| !name.starts_with(WHITEOUT_PREFIX)),
+            );
+        }
+        if !opaque && self.in_lower(&components) && self.lower.is_dir(&path) {
+            for name in self.lower.list_dir(&path)? {
+                let mut child = components.clone();
+                child.push(name.clone());
+                if !self.masked(&child) {
+                    names.push(name);
+                }
+            }
+        }
+        // Sort so callers get the same order on every listing
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    // Folders only in the upper layer can be renamed; moving one the lower layer has
+    // would mean copying up all of it, so that fails with CrossDevice as on Linux
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let from_components = Self::split(from)?;
+        let to_components = Self::split(to)?;
+        if from_components.is_empty() || to_components.is_empty() {
+            return Err(FileSystemError::InvalidType);
+        }
+        let metadata = self.merged_stat(&from_components)?;
+        let in_lower = self.in_lower(&from_components);
+        if metadata.is_dir && in_lower {
+            return Err(FileSystemError::CrossDevice);
+        }
+        if let Ok(existing) = self.merged_stat(&to_components) {
+            if existing.is_dir != metadata.is_dir {
+                return Err(FileSystemError::InvalidType);
+            }
+            if existing.is_dir && !self.list_dir(to)?.is_empty() {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+        }
+
+        self.copy_up(&from_components)?;
+        let replaces_removed = self.prepare_new(&to_components)?;
+        self.upper
+            .rename(&path_of(&from_components), &path_of(&to_components))?;
+        if metadata.is_dir && replaces_removed {
+            self.write_marker(&to_components, true)?;
+        }
+        if in_lower {
+            self.write_marker(&from_components, false)?;
+        }
+
+        for desc in self.file_descriptors.values_mut() {
+            if desc.path.starts_with(&from_components) {
+                desc.path
+                    .splice(..from_components.len(), to_components.iter().cloned());
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, FileSystemError> {
+        self.merged_stat(&Self::split(path)?)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        Self::split(path)
+            .is_ok_and(|components| self.in_upper(&components) || self.in_lower(&components))
+    }
+
+    fn chmod(&mut self, path: &str, permissions: Permissions) -> Result<(), FileSystemError> {
+        let components = Self::split(path)?;
+        self.copy_up(&components)?;
+        self.upper.chmod(&path_of(&components), permissions)
+    }
+
+    fn open(&mut self, path: &str) -> Result<usize, FileSystemError> {
+        self.open_with(path, OpenFlags::READ | OpenFlags::WRITE)
+    }
+
+    // A lower file is opened for reading only and copied up on the first change, even
+    // when it was opened for writing
+    fn open_with(&mut self, path: &str, flags: OpenFlags) -> Result<usize, FileSystemError> {
+        let components = Self::split(path)?;
+        let path = path_of(&components);
+        let in_upper = self.in_upper(&components);
+        let in_lower = !in_upper && self.in_lower(&components);
+        if (in_upper || in_lower) && flags.contains(OpenFlags::CREATE | OpenFlags::EXCL) {
+            return Err(FileSystemError::FileExists);
+        }
+
+        let (layer, fd) = if in_lower && !flags.contains(OpenFlags::TRUNC) {
+            (Layer::Lower, self.lower.open_with(&path, OpenFlags::READ)?)
+        } else if in_upper || in_lower {
+            self.copy_up(&components)?;
+            (Layer::Upper, self.upper.open_with(&path, flags)?)
+        } else if flags.contains(OpenFlags::CREATE) {
+            self.prepare_new(&components)?;
+            (Layer::Upper, self.upper.open_with(&path, flags)?)
+        } else {
+            return Err(FileSystemError::FileNotFound);
+        };
+        Ok(self.allocate_fd(layer, fd, components, flags))
+    }
+
+    fn close(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        let (layer, inner_fd) = self.inner_mut(fd)?;
+        layer.close(inner_fd)?;
+        self.file_descriptors.remove(&fd);
+        Ok(())
+    }
+
+    fn write(&mut self, fd: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        self.copy_up_descriptor(fd)?;
+        let (layer, inner_fd) = self.inner_mut(fd)?;
+        layer.write(inner_fd, data)
+    }
+
+    fn read(&mut self, fd: usize, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        // Lower files are opened for reading whatever was asked for
+        if !self.descriptor(fd)?.flags.contains(OpenFlags::READ) {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let (layer, inner_fd) = self.inner_mut(fd)?;
+        layer.read(inner_fd, buffer)
+    }
+
+    fn seek(&mut self, fd: usize, offset: OffsetFrom) -> Result<usize, FileSystemError> {
+        let (layer, inner_fd) = self.inner_mut(fd)?;
+        layer.seek(inner_fd, offset)
+    }
+
+    fn tell(&self, fd: usize) -> Result<usize, FileSystemError> {
+        let (layer, inner_fd) = self.inner(fd)?;
+        layer.tell(inner_fd)
+    }
+
+    fn ftruncate(&mut self, fd: usize, len: usize) -> Result<(), FileSystemError> {
+        self.copy_up_descriptor(fd)?;
+        let (layer, inner_fd) = self.inner_mut(fd)?;
+        layer.ftruncate(inner_fd, len)
+    }
+
+    fn fstat(&self, fd: usize) -> Result<Metadata, FileSystemError> {
+        let (layer, inner_fd) = self.inner(fd)?;
+        layer.fstat(inner_fd)
+    }
+
+    fn flush(&mut self, fd: usize) -> Result<(), FileSystemError> {
+        let (layer, inner_fd) = self.inner_mut(fd)?;
+        layer.flush(inner_fd)
+    }
+
+    // The lower layer is never written, so only the upper one has anything to sync
+    fn sync_all(&mut self) -> Result<(), FileSystemError> {
+        self.upper.sync_all()
+    }
+}
+
+// Where mount_with keeps its data
+#[allow(dead_code)]
+enum MountMode {
+    InMemory,
+    Persistent(PathBuf),
+}
+
+// Function to mount the file system
+fn mount() -> Box<dyn FileSystem> {
+    Box::new(SimpleFileSystem::new())
+}
+
+#[allow(dead_code)]
+fn mount_readonly() -> Box<dyn FileSystem> {
+    Box::new(SimpleFileSystem::builder().read_only(true).build())
+}
+
+#[allow(dead_code)]
+fn mount_with(mode: MountMode) -> Result<Box<dyn FileSystem>, FileSystemError> {
+    match mode {
+        MountMode::InMemory => Ok(mount()),
+        MountMode::Persistent(root) => Ok(Box::new(PersistentFileSystem::new(root)?)),
+    }
+}
+
+// The demo only exercises part of the file system. What it leaves out is used by the
+// tests, and is marked allow(dead_code) where it is declared.
+fn main() {
+    let mut fs = mount();
+    println!("File system mounted successfully!");
+
+    // Test creating a file
+    match fs.create("/new_file.txt", Permissions::ReadWrite) {
+        Ok(fd) => {
+            println!("File created successfully with file descriptor: {}", fd);
+
+            // Test writing to the file
+            match fs.write(fd, b"Hello, world!") {
+                Ok(()) => println!("Data written successfully."),
+                Err(e) => println!("Error writing data: {:?}", e),
+            }
+
+            // Test seeking to the start of the file
+            match fs.seek(fd, OffsetFrom::Start(0)) {
+                Ok(pos) => println!("Seeked to position: {}", pos),
+                Err(e) => println!("Error seeking file: {:?}", e),
+            }
+
+            // Test reading from the file
+            let mut buffer = [0; 13];
+            match fs.read(fd, &mut buffer) {
+                Ok(bytes_read) => {
+                    println!(
+                        "Read {} bytes: {:?}",
+                        bytes_read,
+                        String::from_utf8_lossy(&buffer)
+                    );
+                }
+                Err(e) => println!("Error reading data: {:?}", e),
+            }
+
+            // Test seeking to the end of the file
+            match fs.seek(fd, OffsetFrom::End(-6)) {
+                Ok(pos) => println!("Seeked to position: {}", pos),
+                Err(e) => println!("Error seeking file: {:?}", e),
+            }
+
+            // Test reading from the new position
+            let mut buffer = [0; 6];
+            match fs.read(fd, &mut buffer) {
+                Ok(bytes_read) => {
+                    println!(
+                        "Read {} bytes: {:?}",
+                        bytes_read,
+                        String::from_utf8_lossy(&buffer)
+                    );
+                }
+                Err(e) => println!("Error reading data: {:?}", e),
+            }
+
+            // Test closing the file
+            match fs.close(fd) {
+                Ok(()) => println!("File closed successfully."),
+                Err(e) => println!("Error closing file: {:?}", e),
+            }
+        }
+        Err(e) => println!("Error creating file: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_is_visible_after_reopening() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a.txt", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        fs.close(fd).unwrap();
+
+        let fd = fs.open("/a.txt").unwrap();
+        let mut buffer = [0; 5];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer, b"hello");
+    }
+
+    #[test]
+    fn write_overwrites_at_the_position() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a.txt", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"Hello").unwrap();
+        fs.seek(fd, OffsetFrom::Start(2)).unwrap();
+        fs.write(fd, b"XY").unwrap();
+        assert_eq!(fs.seek(fd, OffsetFrom::Current(0)).unwrap(), 4);
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        let mut buffer = [0; 5];
+        fs.read(fd, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"HeXYo");
+    }
+
+    #[test]
+    fn read_past_the_end_reads_nothing() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a.txt", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.seek(fd, OffsetFrom::End(1)).unwrap();
+
+        let mut buffer = [7; 4];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 0);
+        assert_eq!(buffer, [7; 4]);
+    }
+
+    #[test]
+    fn mkdir_makes_a_folder_to_create_files_in() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/docs", Permissions::ReadWrite).unwrap();
+        fs.create("/docs/a.txt", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.mkdir("/docs", Permissions::ReadWrite),
+            Err(FileSystemError::FileExists)
+        ));
+        assert!(matches!(
+            fs.mkdir("/missing/docs", Permissions::ReadWrite),
+            Err(FileSystemError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn create_dir_all_completes_a_half_existing_path() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/a", Permissions::ReadWrite).unwrap();
+        fs.create("/a/kept", Permissions::ReadWrite).unwrap();
+        fs.create_dir_all("/a/b/c", Permissions::ReadWrite).unwrap();
+        fs.create("/a/b/c/f", Permissions::ReadWrite).unwrap();
+        fs.open("/a/kept").unwrap();
+
+        // Running it again over folders that all exist is fine
+        fs.create_dir_all("/a/b/c", Permissions::ReadWrite).unwrap();
+        fs.open("/a/b/c/f").unwrap();
+    }
+
+    #[test]
+    fn create_dir_all_through_a_file_is_invalid_type() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/f", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.create_dir_all("/f/a/b", Permissions::ReadWrite),
+            Err(FileSystemError::InvalidType)
+        ));
+        assert!(matches!(
+            fs.create_dir_all("/f", Permissions::ReadWrite),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn unlinked_files_stay_readable_through_open_descriptors() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"data").unwrap();
+        fs.unlink("/a").unwrap();
+        assert!(matches!(fs.open("/a"), Err(FileSystemError::FileNotFound)));
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        let mut buffer = [0; 4];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"data");
+    }
+
+    #[test]
+    fn unlink_refuses_folders_and_missing_files() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        assert!(matches!(fs.unlink("/d"), Err(FileSystemError::InvalidType)));
+        assert!(matches!(
+            fs.unlink("/missing"),
+            Err(FileSystemError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn rmdir_removes_an_empty_folder() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.rmdir("/d").unwrap();
+        assert!(matches!(
+            fs.list_dir("/d"),
+            Err(FileSystemError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn rmdir_refuses_a_folder_with_entries() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.create("/d/f", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.rmdir("/d"),
+            Err(FileSystemError::DirectoryNotEmpty)
+        ));
+        assert!(matches!(
+            fs.rmdir("/d/f"),
+            Err(FileSystemError::InvalidType)
+        ));
+        assert_eq!(fs.list_dir("/d").unwrap(), vec!["f"]);
+    }
+
+    #[test]
+    fn rmdir_refuses_the_root() {
+        let mut fs = SimpleFileSystem::new();
+        assert!(matches!(
+            fs.rmdir("/"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        assert!(fs.list_dir("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn list_dir_returns_sorted_names() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.create("/d/b", Permissions::ReadWrite).unwrap();
+        fs.mkdir("/d/c", Permissions::ReadWrite).unwrap();
+        fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.list_dir("/d").unwrap(), vec!["a", "b", "c"]);
+        assert!(fs.list_dir("/d/c").unwrap().is_empty());
+
+        assert!(matches!(
+            fs.list_dir("/d/a"),
+            Err(FileSystemError::InvalidType)
+        ));
+        assert!(matches!(
+            fs.list_dir("/missing"),
+            Err(FileSystemError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn permissions_are_enforced_on_read_and_write() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/read-only", Permissions::Read).unwrap();
+        assert!(matches!(
+            fs.write(fd, b"x"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+
+        let fd = fs.create("/write-only", Permissions::Write).unwrap();
+        fs.write(fd, b"x").unwrap();
+        let mut buffer = [0; 1];
+        assert!(matches!(
+            fs.read(fd, &mut buffer),
+            Err(FileSystemError::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn read_only_descriptors_refuse_writes() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"data").unwrap();
+
+        let read_only = fs.open_with("/a", OpenFlags::READ).unwrap();
+        assert!(matches!(
+            fs.write(read_only, b"x"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        // Plain open still reads and writes
+        let both = fs.open("/a").unwrap();
+        fs.write(both, b"D").unwrap();
+    }
+
+    #[test]
+    fn trunc_and_create_flags() {
+        let mut fs = SimpleFileSystem::new();
+        assert!(matches!(
+            fs.open_with("/new", OpenFlags::WRITE),
+            Err(FileSystemError::FileNotFound)
+        ));
+        let fd = fs
+            .open_with("/new", OpenFlags::WRITE | OpenFlags::CREATE)
+            .unwrap();
+        fs.write(fd, b"data").unwrap();
+
+        let fd = fs
+            .open_with(
+                "/new",
+                OpenFlags::READ | OpenFlags::WRITE | OpenFlags::TRUNC,
+            )
+            .unwrap();
+        let mut buffer = [0; 4];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn append_descriptors_always_write_at_the_end() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/log", Permissions::ReadWrite).unwrap();
+        let first = fs
+            .open_with("/log", OpenFlags::WRITE | OpenFlags::APPEND)
+            .unwrap();
+        let second = fs
+            .open_with("/log", OpenFlags::WRITE | OpenFlags::APPEND)
+            .unwrap();
+
+        fs.write(first, b"1").unwrap();
+        fs.write(second, b"2").unwrap();
+        fs.seek(first, OffsetFrom::Start(0)).unwrap();
+        fs.write(first, b"3").unwrap();
+        fs.write(second, b"4").unwrap();
+
+        let fd = fs.open("/log").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"1234");
+    }
+
+    #[test]
+    fn ftruncate_shrinks_and_clamps_the_position() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        fs.ftruncate(fd, 2).unwrap();
+        assert_eq!(fs.seek(fd, OffsetFrom::Current(0)).unwrap(), 2);
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"he");
+    }
+
+    #[test]
+    fn ftruncate_grows_with_zeros() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"he").unwrap();
+        fs.ftruncate(fd, 4).unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"he\0\0");
+    }
+
+    #[test]
+    fn ftruncate_rejects_read_only_files_and_folders() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/r", Permissions::Read).unwrap();
+        assert!(matches!(
+            fs.ftruncate(fd, 1),
+            Err(FileSystemError::PermissionDenied)
+        ));
+
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.open_with("/d", OpenFlags::READ).unwrap();
+        assert!(matches!(
+            fs.ftruncate(fd, 1),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn rename_moves_a_file_into_another_folder() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"A").unwrap();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+
+        fs.rename("/a", "/d/b").unwrap();
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["d"]);
+        assert_eq!(fs.list_dir("/d").unwrap(), vec!["b"]);
+        let fd = fs.open("/d/b").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"A");
+    }
+
+    #[test]
+    fn rename_within_a_folder() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.create("/d/b", Permissions::ReadWrite).unwrap();
+        fs.rename("/d/b", "/d/c").unwrap();
+        assert_eq!(fs.list_dir("/d").unwrap(), vec!["c"]);
+    }
+
+    #[test]
+    fn rename_overwrites_files_but_not_folders_with_entries() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"new").unwrap();
+        fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.rename("/a", "/b").unwrap();
+        let fd = fs.open("/b").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"new");
+
+        fs.mkdir("/full", Permissions::ReadWrite).unwrap();
+        fs.create("/full/x", Permissions::ReadWrite).unwrap();
+        fs.mkdir("/e", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.rename("/e", "/full"),
+            Err(FileSystemError::DirectoryNotEmpty)
+        ));
+    }
+
+    #[test]
+    fn rename_errors() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/f", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.rename("/nope", "/q"),
+            Err(FileSystemError::FileNotFound)
+        ));
+        fs.create("/g", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.rename("/g", "/f/z"),
+            Err(FileSystemError::InvalidType)
+        ));
+        assert!(fs.exists("/g"));
+    }
+
+    #[test]
+    fn copy_is_independent_of_the_original() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"A").unwrap();
+        fs.copy("/a", "/b").unwrap();
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        fs.write(fd, b"Z").unwrap();
+        let copy = fs.open("/b").unwrap();
+        assert_eq!(fs.read_to_end(copy).unwrap(), b"A");
+    }
+
+    #[test]
+    fn copy_keeps_permissions_and_refuses_existing_or_folders() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/a", Permissions::Read).unwrap();
+        fs.copy("/a", "/b").unwrap();
+        assert_eq!(fs.stat("/b").unwrap().permissions, Permissions::Read);
+        assert!(matches!(
+            fs.copy("/a", "/b"),
+            Err(FileSystemError::FileExists)
+        ));
+
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.copy("/d", "/e"),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn seeking_before_the_start_is_an_invalid_seek() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        assert!(matches!(
+            fs.seek(fd, OffsetFrom::End(-4)),
+            Err(FileSystemError::InvalidSeek)
+        ));
+        assert!(matches!(
+            fs.seek(fd, OffsetFrom::Current(-4)),
+            Err(FileSystemError::InvalidSeek)
+        ));
+        assert_eq!(fs.seek(fd, OffsetFrom::Current(-3)).unwrap(), 0);
+    }
+
+    #[test]
+    fn writing_past_the_end_fills_the_gap_with_zeros() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"0123456789").unwrap();
+        assert_eq!(fs.seek(fd, OffsetFrom::Start(50)).unwrap(), 50);
+        let mut buffer = [0; 2];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 0);
+
+        fs.write(fd, b"X").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        let data = fs.read_to_end(fd).unwrap();
+        assert_eq!(data.len(), 51);
+        assert!(data[10..50].iter().all(|&byte| byte == 0));
+        assert_eq!(data[50], b'X');
+    }
+
+    #[test]
+    fn reader_reads_to_the_end_then_gives_zero() {
+        use std::io::Read;
+
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello world").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+
+        let mut reader = fs.reader(fd).unwrap();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello world");
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+        assert!(fs.reader(fd + 1).is_err());
+    }
+
+    #[test]
+    fn io_copy_between_two_handles() {
+        use std::io::Write;
+
+        let mut source_fs = SimpleFileSystem::new();
+        let from = source_fs.create("/from", Permissions::ReadWrite).unwrap();
+        write!(source_fs.writer(from).unwrap(), "n={}", 42).unwrap();
+        source_fs.seek(from, OffsetFrom::Start(0)).unwrap();
+
+        let mut target_fs = SimpleFileSystem::new();
+        let to = target_fs.create("/to", Permissions::ReadWrite).unwrap();
+        let mut source = source_fs.reader(from).unwrap();
+        let mut target = target_fs.writer(to).unwrap();
+        assert_eq!(io::copy(&mut source, &mut target).unwrap(), 4);
+        target_fs.seek(to, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(target_fs.read_to_end(to).unwrap(), b"n=42");
+    }
+
+    #[test]
+    fn handles_move_the_descriptor_position() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.writer(fd).unwrap().write_all(b"abcdef").unwrap();
+        assert_eq!(fs.tell(fd).unwrap(), 6);
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        let mut start = [0; 2];
+        fs.reader(fd).unwrap().read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"ab");
+        let mut rest = [0; 8];
+        let bytes_read = fs.read(fd, &mut rest).unwrap();
+        assert_eq!(&rest[..bytes_read], b"cdef");
+
+        fs.reader(fd).unwrap().seek(SeekFrom::Start(1)).unwrap();
+        assert_eq!(fs.tell(fd).unwrap(), 1);
+    }
+
+    #[test]
+    fn read_to_string_reads_multi_byte_utf8() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, "héllo ✓".as_bytes()).unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_string(fd).unwrap(), "héllo ✓");
+        assert!(fs.read_to_end(fd).unwrap().is_empty());
+
+        // Starting inside the two-byte é leaves invalid UTF-8
+        fs.seek(fd, OffsetFrom::Start(2)).unwrap();
+        assert!(matches!(
+            fs.read_to_string(fd),
+            Err(FileSystemError::InvalidUtf8)
+        ));
+        fs.seek(fd, OffsetFrom::Start(100)).unwrap();
+        assert!(fs.read_to_end(fd).unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_all_writes_everything_and_an_empty_slice_is_a_no_op() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write_all(fd, b"ab").unwrap();
+        fs.write_all(fd, b"").unwrap();
+        assert_eq!(fs.seek(fd, OffsetFrom::Current(0)).unwrap(), 2);
+        assert_eq!(fs.fstat(fd).unwrap().size, 2);
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"ab");
+    }
+
+    #[test]
+    fn stat_reports_files_and_folders() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.chmod("/d", Permissions::Read).unwrap();
+
+        let file = fs.stat("/d/a").unwrap();
+        assert!(!file.is_dir);
+        assert_eq!(file.size, 3);
+        assert_eq!(file.permissions, Permissions::ReadWrite);
+
+        let folder = fs.stat("/d").unwrap();
+        assert!(folder.is_dir);
+        assert_eq!(folder.size, 1);
+        assert_eq!(folder.permissions, Permissions::Read);
+    }
+
+    #[test]
+    fn fstat_sees_writes_through_other_descriptors() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let other = fs.open("/a").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().size, 0);
+
+        fs.write(other, b"abc").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().size, 3);
+        assert!(matches!(
+            fs.fstat(fd + 100),
+            Err(FileSystemError::InvalidFileDescriptor)
+        ));
+    }
+
+    #[test]
+    fn chmod_makes_a_read_only_file_writable() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::Read).unwrap();
+        assert!(matches!(
+            fs.write(fd, b"x"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        fs.chmod("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"x").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().size, 1);
+    }
+
+    #[test]
+    fn read_only_folders_refuse_new_and_removed_entries() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.mkdir("/d/sub", Permissions::ReadWrite).unwrap();
+        fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.chmod("/d", Permissions::Read).unwrap();
+
+        let denied = |result: Result<(), FileSystemError>| {
+            assert!(matches!(result, Err(FileSystemError::PermissionDenied)));
+        };
+        denied(fs.create("/d/b", Permissions::ReadWrite).map(|_| ()));
+        denied(fs.mkdir("/d/e", Permissions::ReadWrite));
+        denied(fs.create_dir_all("/d/e/f", Permissions::ReadWrite));
+        denied(fs.symlink("/d/a", "/d/l"));
+        denied(fs.link("/d/a", "/d/h"));
+        denied(fs.unlink("/d/a"));
+        denied(fs.rmdir("/d/sub"));
+        denied(fs.remove_dir_all("/d/sub"));
+        denied(fs.rename("/d/a", "/a"));
+        fs.create("/b", Permissions::ReadWrite).unwrap();
+        denied(fs.rename("/b", "/d/b"));
+        assert_eq!(fs.list_dir("/d").unwrap(), vec!["a", "sub"]);
+
+        // The entries themselves stay writable
+        let fd = fs.open("/d/a").unwrap();
+        fs.write(fd, b"x").unwrap();
+    }
+
+    #[test]
+    fn chmod_works_on_folders_and_needs_an_existing_path() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.chmod("/d", Permissions::Read).unwrap();
+        assert_eq!(fs.stat("/d").unwrap().permissions, Permissions::Read);
+        assert!(matches!(
+            fs.chmod("/z", Permissions::Read),
+            Err(FileSystemError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn each_error_has_its_own_message() {
+        let cases = [
+            (
+                FileSystemError::InvalidType,
+                "wrong node type for this operation",
+            ),
+            (FileSystemError::PermissionDenied, "permission denied"),
+            (FileSystemError::FileNotFound, "no such file or directory"),
+            (FileSystemError::FileExists, "file exists"),
+            (FileSystemError::DirectoryNotEmpty, "directory not empty"),
+            (
+                FileSystemError::InvalidFileDescriptor,
+                "bad file descriptor",
+            ),
+            (FileSystemError::InvalidSeek, "invalid seek"),
+            (
+                FileSystemError::InvalidUtf8,
+                "file contents are not valid UTF-8",
+            ),
+            (
+                FileSystemError::TooManySymlinks,
+                "too many levels of symbolic links",
+            ),
+            (FileSystemError::WouldBlock, "file is locked"),
+            (FileSystemError::TooManyOpenFiles, "too many open files"),
+            (FileSystemError::ReadOnlyFileSystem, "read-only file system"),
+            (
+                FileSystemError::Poisoned,
+                "a thread panicked while holding a lock",
+            ),
+            (FileSystemError::QuotaExceeded, "file system quota exceeded"),
+            (FileSystemError::InvalidName, "invalid file name"),
+            (FileSystemError::XattrNotFound, "no such extended attribute"),
+            (
+                FileSystemError::InvalidMove,
+                "cannot move a folder inside itself",
+            ),
+            (FileSystemError::VersionNotFound, "no such earlier version"),
+            (FileSystemError::FileTooLarge, "file too large"),
+            (
+                FileSystemError::CrossDevice,
+                "cannot move across mount points",
+            ),
+            (
+                FileSystemError::Serialization("bad".to_string()),
+                "could not serialize file system: bad",
+            ),
+            (
+                FileSystemError::Io(io::ErrorKind::Interrupted),
+                "host I/O error: operation interrupted",
+            ),
+        ];
+        for (err, message) in cases {
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn errors_map_to_io_error_kinds() {
+        let cases = [
+            (FileSystemError::InvalidType, io::ErrorKind::InvalidInput),
+            (
+                FileSystemError::PermissionDenied,
+                io::ErrorKind::PermissionDenied,
+            ),
+            (FileSystemError::FileNotFound, io::ErrorKind::NotFound),
+            (FileSystemError::FileExists, io::ErrorKind::AlreadyExists),
+            (
+                FileSystemError::DirectoryNotEmpty,
+                io::ErrorKind::DirectoryNotEmpty,
+            ),
+            (
+                FileSystemError::InvalidFileDescriptor,
+                io::ErrorKind::InvalidInput,
+            ),
+            (FileSystemError::InvalidSeek, io::ErrorKind::InvalidInput),
+            (FileSystemError::InvalidUtf8, io::ErrorKind::InvalidData),
+            (
+                FileSystemError::TooManySymlinks,
+                io::ErrorKind::InvalidInput,
+            ),
+            (FileSystemError::WouldBlock, io::ErrorKind::WouldBlock),
+            (FileSystemError::TooManyOpenFiles, io::ErrorKind::Other),
+            (
+                FileSystemError::ReadOnlyFileSystem,
+                io::ErrorKind::ReadOnlyFilesystem,
+            ),
+            (FileSystemError::Poisoned, io::ErrorKind::Other),
+            (FileSystemError::QuotaExceeded, io::ErrorKind::QuotaExceeded),
+            (FileSystemError::InvalidName, io::ErrorKind::InvalidFilename),
+            (FileSystemError::XattrNotFound, io::ErrorKind::NotFound),
+            (FileSystemError::InvalidMove, io::ErrorKind::InvalidInput),
+            (FileSystemError::VersionNotFound, io::ErrorKind::NotFound),
+            (FileSystemError::FileTooLarge, io::ErrorKind::FileTooLarge),
+            (FileSystemError::CrossDevice, io::ErrorKind::CrossesDevices),
+            (
+                FileSystemError::Serialization(String::new()),
+                io::ErrorKind::InvalidData,
+            ),
+            (
+                FileSystemError::Io(io::ErrorKind::Interrupted),
+                io::ErrorKind::Interrupted,
+            ),
+        ];
+        for (err, kind) in cases {
+            assert_eq!(io::Error::from(err).kind(), kind);
+        }
+    }
+
+    #[test]
+    fn errors_work_with_the_question_mark_in_io_code() {
+        fn open(fs: &mut SimpleFileSystem, path: &str) -> io::Result<usize> {
+            Ok(fs.open(path)?)
+        }
+        let mut fs = SimpleFileSystem::new();
+        assert_eq!(
+            open(&mut fs, "/missing").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn repeated_and_trailing_slashes_are_collapsed() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/a", Permissions::ReadWrite).unwrap();
+        fs.mkdir("//a//b/", Permissions::ReadWrite).unwrap();
+        fs.create("/a/b/f", Permissions::ReadWrite).unwrap();
+        assert!(fs.stat("//a//b/").unwrap().is_dir);
+        assert_eq!(fs.list_dir("/a").unwrap(), vec!["b"]);
+        assert!(fs.open("a//b/f/").is_ok());
+    }
+
+    #[test]
+    fn slash_and_empty_paths_name_the_root() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/a", Permissions::ReadWrite).unwrap();
+        assert!(fs.stat("/").unwrap().is_dir);
+        assert_eq!(fs.stat("").unwrap().size, 1);
+        assert_eq!(fs.list_dir("").unwrap(), vec!["a"]);
+        assert!(matches!(
+            fs.mkdir("/", Permissions::ReadWrite),
+            Err(FileSystemError::FileExists)
+        ));
+    }
+
+    #[test]
+    fn dot_components_stay_in_place() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/a", Permissions::ReadWrite).unwrap();
+        fs.create("/a/./b.txt", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.list_dir("/a").unwrap(), vec!["b.txt"]);
+        assert!(fs.open("/./a/b.txt").is_ok());
+    }
+
+    #[test]
+    fn dot_dot_pops_but_never_past_the_root() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/a", Permissions::ReadWrite).unwrap();
+        fs.create("/a/../c", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["a", "c"]);
+        assert!(fs.open("/../../c").is_ok());
+        assert!(fs.stat("/..").unwrap().is_dir);
+    }
+
+    #[test]
+    fn relative_paths_start_at_the_working_directory() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/docs", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/docs/a.txt", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"docs").unwrap();
+        assert_eq!(fs.getcwd(), "/");
+
+        fs.chdir("/docs").unwrap();
+        assert_eq!(fs.getcwd(), "/docs");
+        let fd = fs.open("a.txt").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"docs");
+        fs.mkdir("sub", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.list_dir("/docs").unwrap(), vec!["a.txt", "sub"]);
+
+        fs.chdir("..").unwrap();
+        assert_eq!(fs.getcwd(), "/");
+    }
+
+    #[test]
+    fn chdir_needs_a_folder() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/f", Permissions::ReadWrite).unwrap();
+        assert!(matches!(fs.chdir("/f"), Err(FileSystemError::InvalidType)));
+        assert!(matches!(
+            fs.chdir("/missing"),
+            Err(FileSystemError::FileNotFound)
+        ));
+        assert_eq!(fs.getcwd(), "/");
+    }
+
+    #[test]
+    fn symlinks_to_files_are_followed() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/f", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hi").unwrap();
+        fs.symlink("/d/f", "/l").unwrap();
+        fs.symlink("f", "/d/relative").unwrap();
+
+        let fd = fs.open("/l").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"hi");
+        let fd = fs.open("/d/relative").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"hi");
+        assert_eq!(fs.readlink("/l").unwrap(), "/d/f");
+        assert!(matches!(
+            fs.readlink("/d/f"),
+            Err(FileSystemError::InvalidType)
+        ));
+
+        fs.unlink("/l").unwrap();
+        assert!(fs.open("/d/f").is_ok());
+    }
+
+    #[test]
+    fn dangling_symlinks_are_not_found() {
+        let mut fs = SimpleFileSystem::new();
+        fs.symlink("/nowhere", "/dangling").unwrap();
+        assert!(matches!(
+            fs.open("/dangling"),
+            Err(FileSystemError::FileNotFound)
+        ));
+        assert_eq!(fs.readlink("/dangling").unwrap(), "/nowhere");
+    }
+
+    #[test]
+    fn symlink_loops_are_cut_off() {
+        let mut fs = SimpleFileSystem::new();
+        fs.symlink("/loop", "/loop").unwrap();
+        assert!(matches!(
+            fs.open("/loop"),
+            Err(FileSystemError::TooManySymlinks)
+        ));
+    }
+
+    #[test]
+    fn hard_links_share_contents() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.link("/a", "/b").unwrap();
+        fs.write(fd, b"shared").unwrap();
+
+        let fd = fs.open("/b").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"shared");
+        assert_eq!(fs.stat("/a").unwrap().links, 2);
+        fs.unlink("/a").unwrap();
+        assert_eq!(fs.stat("/b").unwrap().links, 1);
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"shared");
+    }
+
+    #[test]
+    fn hard_links_need_a_file_and_a_free_name() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.create("/a", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.link("/d", "/e"),
+            Err(FileSystemError::InvalidType)
+        ));
+        assert!(matches!(
+            fs.link("/a", "/a"),
+            Err(FileSystemError::FileExists)
+        ));
+    }
+
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn writes_bump_modified_and_reads_only_accessed() {
+        let time = Arc::new(AtomicU64::new(10));
+        let mut fs = SimpleFileSystem::with_clock(FakeClock(time.clone()));
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.stat("/a").unwrap().created, at(10));
+
+        time.store(20, Ordering::SeqCst);
+        fs.write(fd, b"x").unwrap();
+        let metadata = fs.stat("/a").unwrap();
+        assert_eq!(metadata.modified, at(20));
+        assert_eq!(metadata.accessed, at(10));
+
+        time.store(30, Ordering::SeqCst);
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        fs.read(fd, &mut [0; 1]).unwrap();
+        let metadata = fs.stat("/a").unwrap();
+        assert_eq!(metadata.modified, at(20));
+        assert_eq!(metadata.accessed, at(30));
+
+        time.store(40, Ordering::SeqCst);
+        fs.ftruncate(fd, 0).unwrap();
+        assert_eq!(fs.stat("/a").unwrap().modified, at(40));
+    }
+
+    #[test]
+    fn dup_has_its_own_position() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abcdef").unwrap();
+        let copy = fs.dup(fd).unwrap();
+        assert_ne!(copy, fd);
+        assert_eq!(fs.seek(copy, OffsetFrom::Current(0)).unwrap(), 6);
+
+        fs.seek(fd, OffsetFrom::Start(1)).unwrap();
+        assert_eq!(fs.seek(copy, OffsetFrom::Current(0)).unwrap(), 6);
+
+        fs.close(fd).unwrap();
+        fs.seek(copy, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(copy).unwrap(), b"abcdef");
+        assert!(matches!(
+            fs.dup(fd),
+            Err(FileSystemError::InvalidFileDescriptor)
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn save_and_load_round_trip_the_tree() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"\x00\xffhi").unwrap();
+        fs.chmod("/d", Permissions::Read).unwrap();
+        fs.symlink("/d/a", "/l").unwrap();
+
+        let mut saved = Vec::new();
+        fs.save_to_writer(&mut saved).unwrap();
+        // File data is written as base64
+        assert!(String::from_utf8(saved.clone())
+            .unwrap()
+            .contains("AP9oaQ=="));
+
+        let mut loaded = SimpleFileSystem::load_from_reader(&saved[..]).unwrap();
+        assert_eq!(loaded.list_dir("/").unwrap(), vec!["d", "l"]);
+        assert_eq!(loaded.stat("/d").unwrap().permissions, Permissions::Read);
+        assert_eq!(loaded.readlink("/l").unwrap(), "/d/a");
+        let fd = loaded.open("/l").unwrap();
+        assert_eq!(loaded.read_to_end(fd).unwrap(), b"\x00\xffhi");
+        assert!(loaded.fsck().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn loading_bad_json_is_a_serialization_error() {
+        assert!(matches!(
+            SimpleFileSystem::load_from_reader(&b"{"[..]),
+            Err(FileSystemError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn persistent_files_outlive_the_file_system() {
+        let host = tempfile::tempdir().unwrap();
+        let mut fs = mount_with(MountMode::Persistent(host.path().to_path_buf())).unwrap();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"kept").unwrap();
+        fs.close(fd).unwrap();
+        drop(fs);
+
+        assert_eq!(fs::read(host.path().join("d/a")).unwrap(), b"kept");
+        let mut fs = mount_with(MountMode::Persistent(host.path().to_path_buf())).unwrap();
+        let metadata = fs.stat("/d/a").unwrap();
+        assert_eq!((metadata.size, metadata.links), (4, 1));
+        let fd = fs.open("/d/a").unwrap();
+        let mut buffer = [0; 4];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"kept");
+        assert_eq!(fs.fstat(fd).unwrap().ino, metadata.ino);
+    }
+
+    #[test]
+    fn persistent_paths_stay_inside_the_root() {
+        let host = tempfile::tempdir().unwrap();
+        let root = host.path().join("root");
+        assert!(mount_with(MountMode::Persistent(root.clone())).is_err());
+
+        fs::create_dir(&root).unwrap();
+        let mut fs = mount_with(MountMode::Persistent(root.clone())).unwrap();
+        fs.create("/../../escape", Permissions::ReadWrite).unwrap();
+        assert!(root.join("escape").exists());
+        assert!(!host.path().join("escape").exists());
+    }
+
+    #[test]
+    fn in_memory_mounts_start_empty() {
+        let fs = mount_with(MountMode::InMemory).unwrap();
+        assert!(fs.list_dir("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn positioned_reads_leave_the_position_alone() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello world").unwrap();
+        fs.seek(fd, OffsetFrom::Start(3)).unwrap();
+
+        let mut buffer = [0; 5];
+        assert_eq!(fs.read_at(fd, 6, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer, b"world");
+        assert_eq!(fs.read_at(fd, 0, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer, b"hello");
+        assert_eq!(fs.read_at(fd, 8, &mut buffer).unwrap(), 3);
+        assert_eq!(&buffer[..3], b"rld");
+        assert_eq!(fs.seek(fd, OffsetFrom::Current(0)).unwrap(), 3);
+    }
+
+    #[test]
+    fn positioned_writes_leave_the_position_alone() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello world").unwrap();
+        fs.seek(fd, OffsetFrom::Start(3)).unwrap();
+        fs.write_at(fd, 13, b"!").unwrap();
+        assert_eq!(fs.seek(fd, OffsetFrom::Current(0)).unwrap(), 3);
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"hello world\0\0!");
+        let write_only = fs.open_with("/a", OpenFlags::WRITE).unwrap();
+        assert!(matches!(
+            fs.read_at(write_only, 0, &mut [0; 1]),
+            Err(FileSystemError::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn writes_up_to_the_quota_succeed() {
+        let mut fs = SimpleFileSystem::new_with_quota(10);
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"123456789").unwrap();
+        fs.write(fd, b"0").unwrap();
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 10);
+        // Overwriting in place needs no more room
+        fs.write_at(fd, 0, b"abcde").unwrap();
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn writes_past_the_quota_fail() {
+        let mut fs = SimpleFileSystem::new_with_quota(10);
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"123456789").unwrap();
+        assert!(matches!(
+            fs.write(fd, b"01"),
+            Err(FileSystemError::QuotaExceeded)
+        ));
+        assert!(matches!(
+            fs.write_at(fd, 9, b"01"),
+            Err(FileSystemError::QuotaExceeded)
+        ));
+        assert!(matches!(
+            fs.ftruncate(fd, 11),
+            Err(FileSystemError::QuotaExceeded)
+        ));
+        assert_eq!(fs.fstat(fd).unwrap().size, 9);
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 9);
+    }
+
+    #[test]
+    fn freed_bytes_return_to_the_quota() {
+        let mut fs = SimpleFileSystem::new_with_quota(10);
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"123456789").unwrap();
+        fs.ftruncate(fd, 4).unwrap();
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 4);
+
+        // An unlinked file holds its bytes until its last descriptor closes
+        fs.unlink("/a").unwrap();
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 4);
+        fs.close(fd).unwrap();
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn builder_options_are_applied() {
+        let mut fs = SimpleFileSystem::builder()
+            .starting_fd(100)
+            .max_total_bytes(3)
+            .build();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        assert_eq!(fd, 100);
+        assert_eq!(fs.create("/b", Permissions::ReadWrite).unwrap(), 101);
+        assert!(matches!(
+            fs.write(fd, b"1234"),
+            Err(FileSystemError::QuotaExceeded)
+        ));
+
+        let mut fs = SimpleFileSystem::builder()
+            .root_permissions(Permissions::Read)
+            .build();
+        assert_eq!(fs.stat("/").unwrap().permissions, Permissions::Read);
+        assert!(matches!(
+            fs.create("/a", Permissions::ReadWrite),
+            Err(FileSystemError::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn case_insensitive_mode_matches_any_casing() {
+        let mut fs = SimpleFileSystem::builder().case_insensitive(true).build();
+        fs.mkdir("/Dir", Permissions::ReadWrite).unwrap();
+        fs.create("/DIR/Foo", Permissions::ReadWrite).unwrap();
+        assert!(fs.open("/dir/foo").is_ok());
+        assert!(matches!(
+            fs.create("/dir/FOO", Permissions::ReadWrite),
+            Err(FileSystemError::FileExists)
+        ));
+        // The casing used to create an entry is the one listed
+        assert_eq!(fs.list_dir("/dir").unwrap(), vec!["Foo"]);
+        fs.unlink("/dir/fOO").unwrap();
+        assert!(fs.list_dir("/dir").unwrap().is_empty());
+    }
+
+    #[test]
+    fn default_mode_is_case_sensitive() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/Foo", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.open("/foo"),
+            Err(FileSystemError::FileNotFound)
+        ));
+        fs.create("/foo", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["Foo", "foo"]);
+    }
+
+    #[test]
+    fn over_long_names_are_rejected() {
+        let mut fs = SimpleFileSystem::new();
+        let long = "a".repeat(256);
+        assert!(matches!(
+            fs.create(&format!("/{}", long), Permissions::ReadWrite),
+            Err(FileSystemError::InvalidName)
+        ));
+        fs.create(&format!("/{}", &long[..255]), Permissions::ReadWrite)
+            .unwrap();
+
+        let mut fs = SimpleFileSystem::builder().max_name_len(2).build();
+        fs.create("/ab", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.create("/abc", Permissions::ReadWrite),
+            Err(FileSystemError::InvalidName)
+        ));
+        assert!(matches!(
+            fs.rename("/ab", "/abc"),
+            Err(FileSystemError::InvalidName)
+        ));
+    }
+
+    #[test]
+    fn names_with_nul_are_rejected() {
+        let mut fs = SimpleFileSystem::new();
+        assert!(matches!(
+            fs.mkdir("/a\0b", Permissions::ReadWrite),
+            Err(FileSystemError::InvalidName)
+        ));
+        assert!(matches!(
+            fs.create_dir_all("/x/y/a\0", Permissions::ReadWrite),
+            Err(FileSystemError::InvalidName)
+        ));
+        // Collapsing repeated slashes is not an empty name
+        fs.mkdir("//z//", Permissions::ReadWrite).unwrap();
+        assert!(fs.stat("/z").unwrap().is_dir);
+    }
+
+    #[test]
+    fn remove_dir_all_removes_a_whole_tree() {
+        let mut fs = SimpleFileSystem::new_with_quota(100);
+        fs.create_dir_all("/a/b/c", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/a/b/c/f", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"1234").unwrap();
+        fs.close(fd).unwrap();
+        let fd = fs.create("/a/g", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"12").unwrap();
+        fs.close(fd).unwrap();
+        fs.link("/a/g", "/keep").unwrap();
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 6);
+
+        fs.remove_dir_all("/a").unwrap();
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["keep"]);
+        // Only the file still named outside the tree keeps its bytes
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 2);
+        assert_eq!(fs.stat("/keep").unwrap().links, 1);
+    }
+
+    #[test]
+    fn remove_dir_all_needs_write_permission_throughout() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/b", Permissions::ReadWrite).unwrap();
+        fs.mkdir("/a/b/ro", Permissions::Read).unwrap();
+        assert!(matches!(
+            fs.remove_dir_all("/a"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        assert!(fs.stat("/a/b/ro").unwrap().is_dir);
+    }
+
+    #[test]
+    fn remove_dir_all_errors() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/f", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.remove_dir_all("/a"),
+            Err(FileSystemError::FileNotFound)
+        ));
+        assert!(matches!(
+            fs.remove_dir_all("/f"),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn print_tree_renders_folders_first_in_name_order() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/docs/old", Permissions::ReadWrite)
+            .unwrap();
+        fs.create("/docs/a.txt", Permissions::Read).unwrap();
+        fs.create("/b", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/docs/old/z", Permissions::Write).unwrap();
+        fs.write(fd, b"zz").unwrap();
+        fs.symlink("/docs", "/a").unwrap();
+
+        assert_eq!(
+            fs.print_tree(),
+            "/\n\
+             ├── docs/ (rw)\n\
+             │   ├── old/ (rw)\n\
+             │   │   └── z (2 bytes, -w)\n\
+             │   └── a.txt (0 bytes, r-)\n\
+             ├── a -> /docs\n\
+             └── b (0 bytes, rw)\n"
+        );
+    }
+
+    #[test]
+    fn disk_usage_sums_a_nested_tree() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/b", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/a/b/f", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"1234").unwrap();
+        let fd = fs.create("/a/g", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"12").unwrap();
+        let fd = fs.create("/top", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"123").unwrap();
+
+        assert_eq!(fs.disk_usage("/").unwrap(), 9);
+        assert_eq!(fs.disk_usage("/a").unwrap(), 6);
+        assert_eq!(fs.disk_usage("/a/b").unwrap(), 4);
+        assert_eq!(fs.disk_usage("/a/b/f").unwrap(), 4);
+        assert!(matches!(
+            fs.disk_usage("/x"),
+            Err(FileSystemError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn disk_usage_counts_hard_links_once() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/b", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/a/g", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"12").unwrap();
+        fs.link("/a/g", "/a/b/h").unwrap();
+        assert_eq!(fs.disk_usage("/a").unwrap(), 2);
+    }
+
+    fn find_fixture() -> SimpleFileSystem {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/docs/sub", Permissions::ReadWrite)
+            .unwrap();
+        for path in [
+            "/docs/a.txt",
+            "/docs/ab.txt",
+            "/docs/b.txt",
+            "/docs/c.md",
+            "/docs/sub/c.txt",
+            "/x.txt",
+        ] {
+            fs.create(path, Permissions::ReadWrite).unwrap();
+        }
+        fs
+    }
+
+    #[test]
+    fn find_star_matches_any_run_of_characters() {
+        let fs = find_fixture();
+        assert_eq!(
+            fs.find("/docs", "*.txt").unwrap(),
+            vec![
+                "/docs/a.txt",
+                "/docs/ab.txt",
+                "/docs/b.txt",
+                "/docs/sub/c.txt"
+            ]
+        );
+        assert_eq!(fs.find("/", "s*").unwrap(), vec!["/docs/sub"]);
+        assert_eq!(fs.find("/", "*").unwrap().len(), 8);
+        assert!(matches!(
+            fs.find("/x.txt", "*"),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn find_question_mark_matches_one_character() {
+        let fs = find_fixture();
+        assert_eq!(
+            fs.find("/docs", "?.txt").unwrap(),
+            vec!["/docs/a.txt", "/docs/b.txt", "/docs/sub/c.txt"]
+        );
+        assert_eq!(fs.find("/docs", "??.txt").unwrap(), vec!["/docs/ab.txt"]);
+    }
+
+    #[test]
+    fn glob_match_backtracks_to_the_latest_star() {
+        let matches = |pattern: &str, name: &str| {
+            glob_match(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(matches("a*b*c", "aXbYbc"));
+        assert!(matches("*", ""));
+        assert!(matches("a**", "a"));
+        assert!(!matches("a*b", "aXbY"));
+        assert!(!matches("?", ""));
+        // Would take exponential time with naive backtracking
+        assert!(!matches("*a*a*a*a*a*a*a*b", &"a".repeat(40)));
+    }
+
+    #[test]
+    fn walk_yields_every_path_in_pre_order() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/b", Permissions::ReadWrite).unwrap();
+        fs.create("/a/b/f", Permissions::ReadWrite).unwrap();
+        fs.create("/a/c", Permissions::ReadWrite).unwrap();
+        fs.create("/z", Permissions::ReadWrite).unwrap();
+        fs.symlink("/a", "/a/b/l").unwrap();
+
+        let walk = fs.walk("/").unwrap();
+        // The walk does not borrow the file system, so it can change meanwhile
+        fs.create("/y", Permissions::ReadWrite).unwrap();
+        let paths: Vec<String> = walk.map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["/a", "/a/b", "/a/b/f", "/a/b/l", "/a/c", "/z"]);
+
+        let folders: Vec<String> = fs
+            .walk("/a")
+            .unwrap()
+            .filter(|(_, metadata)| metadata.is_dir)
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(folders, vec!["/a/b"]);
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let mut fs = SimpleFileSystem::new();
+        let first = fs.create("/f", Permissions::ReadWrite).unwrap();
+        let second = fs.open("/f").unwrap();
+        fs.lock(first, false).unwrap();
+        fs.lock(second, false).unwrap();
+        fs.unlock(first).unwrap();
+        fs.unlock(second).unwrap();
+    }
+
+    #[test]
+    fn an_exclusive_lock_blocks_any_other() {
+        let mut fs = SimpleFileSystem::new();
+        let first = fs.create("/f", Permissions::ReadWrite).unwrap();
+        let second = fs.open("/f").unwrap();
+        fs.lock(first, true).unwrap();
+        assert!(matches!(
+            fs.lock(second, true),
+            Err(FileSystemError::WouldBlock)
+        ));
+        assert!(matches!(
+            fs.lock(second, false),
+            Err(FileSystemError::WouldBlock)
+        ));
+
+        // Closing gives the lock up
+        fs.close(first).unwrap();
+        fs.lock(second, true).unwrap();
+        assert!(matches!(
+            fs.lock(99, true),
+            Err(FileSystemError::InvalidFileDescriptor)
+        ));
+    }
+
+    #[test]
+    fn a_shared_lock_blocks_an_exclusive_one() {
+        let mut fs = SimpleFileSystem::new();
+        let first = fs.create("/f", Permissions::ReadWrite).unwrap();
+        let second = fs.open("/f").unwrap();
+        fs.lock(first, false).unwrap();
+        assert!(matches!(
+            fs.lock(second, true),
+            Err(FileSystemError::WouldBlock)
+        ));
+        // The only holder of a shared lock can upgrade it
+        fs.lock(first, true).unwrap();
+    }
+
+    #[test]
+    fn flush_checks_the_descriptor() {
+        let mut fs = SimpleFileSystem::new();
+        assert!(matches!(
+            fs.flush(3),
+            Err(FileSystemError::InvalidFileDescriptor)
+        ));
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.flush(fd).unwrap();
+        fs.sync_all().unwrap();
+    }
+
+    #[test]
+    fn restore_brings_back_a_snapshot() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.link("/d/a", "/b").unwrap();
+        let snapshot = fs.snapshot();
+
+        fs.write(fd, b"zzz").unwrap();
+        fs.remove_dir_all("/d").unwrap();
+        fs.unlink("/b").unwrap();
+        assert!(fs.list_dir("/").unwrap().is_empty());
+
+        fs.restore(snapshot);
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["b", "d"]);
+        // Descriptors from before the restore are gone
+        assert!(matches!(
+            fs.read(fd, &mut [0; 3]),
+            Err(FileSystemError::InvalidFileDescriptor)
+        ));
+        let fd = fs.open("/d/a").unwrap();
+        assert_eq!(fs.read_to_string(fd).unwrap(), "abc");
+        // Hard links come back as links
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        fs.write(fd, b"Q").unwrap();
+        let fd = fs.open("/b").unwrap();
+        assert_eq!(fs.read_to_string(fd).unwrap(), "Qbc");
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn threads_write_disjoint_offsets_without_losing_data() {
+        let mut fs = SimpleFileSystem::new_with_quota(2000);
+        let first = fs.create("/f", Permissions::ReadWrite).unwrap();
+        let second = fs.open("/f").unwrap();
+        std::thread::scope(|scope| {
+            let fs = &fs;
+            scope.spawn(move || {
+                for i in 0..100 {
+                    fs.write_at(first, i * 2, b"a").unwrap();
+                }
+            });
+            scope.spawn(move || {
+                for i in 0..100 {
+                    fs.write_at(second, i * 2 + 1, b"b").unwrap();
+                }
+            });
+        });
+        assert_eq!(fs.read_to_end(first).unwrap(), b"ab".repeat(100));
+        assert_eq!(fs.total_bytes.load(Ordering::SeqCst), 200);
+    }
+
+    #[test]
+    fn a_poisoned_file_is_an_error_not_a_panic() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"x").unwrap();
+        let inode = fs.get_file_descriptor(fd).unwrap();
+        std::thread::spawn(move || {
+            let _guard = inode.lock().unwrap();
+            panic!("poison the file");
+        })
+        .join()
+        .unwrap_err();
+
+        assert!(matches!(
+            fs.read(fd, &mut [0; 1]),
+            Err(FileSystemError::Poisoned)
+        ));
+        assert!(matches!(fs.open("/a"), Err(FileSystemError::Poisoned)));
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn descriptors_stop_at_the_cap() {
+        let mut fs = SimpleFileSystem::builder().max_open_fds(2).build();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.dup(fd).unwrap();
+        assert!(matches!(
+            fs.create("/b", Permissions::ReadWrite),
+            Err(FileSystemError::TooManyOpenFiles)
+        ));
+        // Nothing is created when no descriptor can be handed out for it
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["a"]);
+        assert!(matches!(
+            fs.open("/a"),
+            Err(FileSystemError::TooManyOpenFiles)
+        ));
+        assert!(matches!(fs.dup(fd), Err(FileSystemError::TooManyOpenFiles)));
+    }
+
+    #[test]
+    fn closing_frees_a_slot_and_its_number() {
+        let mut fs = SimpleFileSystem::builder().max_open_fds(2).build();
+        let first = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let second = fs.open("/a").unwrap();
+        fs.close(first).unwrap();
+        assert_eq!(fs.open("/a").unwrap(), first);
+        fs.close(second).unwrap();
+        assert_eq!(fs.open("/a").unwrap(), second);
+        assert_eq!(fs.next_fd, second + 1);
+    }
+
+    #[test]
+    fn a_read_only_mount_reads_but_never_changes() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hi").unwrap();
+        fs.read_only = true;
+
+        let fd = fs.open("/d/a").unwrap();
+        assert_eq!(fs.read_to_string(fd).unwrap(), "hi");
+        assert_eq!(fs.list_dir("/d").unwrap(), vec!["a"]);
+        assert_eq!(fs.stat("/d/a").unwrap().size, 2);
+
+        let read_only = |result: Result<(), FileSystemError>| {
+            matches!(result, Err(FileSystemError::ReadOnlyFileSystem))
+        };
+        assert!(read_only(
+            fs.create("/b", Permissions::ReadWrite).map(|_| ())
+        ));
+        assert!(read_only(fs.write(fd, b"x")));
+        assert!(read_only(fs.mkdir("/x", Permissions::ReadWrite)));
+        assert!(read_only(fs.unlink("/d/a")));
+        assert!(read_only(fs.ftruncate(fd, 0)));
+        assert!(read_only(fs.rename("/d/a", "/z")));
+
+        let mut mounted = mount_readonly();
+        assert!(matches!(
+            mounted.create("/a", Permissions::ReadWrite),
+            Err(FileSystemError::ReadOnlyFileSystem)
+        ));
+    }
+
+    #[test]
+    fn remount_toggles_writes_without_losing_data() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"a").unwrap();
+
+        fs.remount(true);
+        assert!(matches!(
+            fs.write(fd, b"b"),
+            Err(FileSystemError::ReadOnlyFileSystem)
+        ));
+        fs.remount(false);
+        fs.write(fd, b"c").unwrap();
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_string(fd).unwrap(), "ac");
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn tar_import_and_export_round_trip() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o555);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "docs/", io::empty())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o444);
+        header.set_size(5);
+        builder
+            .append_data(&mut header, "docs/a.txt", &b"hello"[..])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(2);
+        builder
+            .append_data(&mut header, "x/y/z", &b"hi"[..])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder.append_link(&mut header, "l", "docs/a.txt").unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let mut fs = SimpleFileSystem::new();
+        fs.import_tar(&archive[..]).unwrap();
+        assert_eq!(fs.stat("/docs").unwrap().permissions, Permissions::Read);
+        assert_eq!(
+            fs.stat("/docs/a.txt").unwrap().permissions,
+            Permissions::Read
+        );
+        assert_eq!(
+            fs.stat("/x/y/z").unwrap().permissions,
+            Permissions::ReadWrite
+        );
+        let fd = fs.open_with("/l", OpenFlags::READ).unwrap();
+        assert_eq!(fs.read_to_string(fd).unwrap(), "hello");
+
+        let mut exported = Vec::new();
+        fs.export_tar(&mut exported).unwrap();
+        let mut names: Vec<String> = tar::Archive::new(&exported[..])
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["docs", "docs/a.txt", "l", "x", "x/y", "x/y/z"]);
+
+        let mut reimported = SimpleFileSystem::new();
+        reimported.import_tar(&exported[..]).unwrap();
+        assert_eq!(reimported.print_tree(), fs.print_tree());
+    }
+
+    #[cfg(feature = "dedup")]
+    fn data_of(fs: &SimpleFileSystem, fd: usize) -> Arc<Vec<u8>> {
+        match &*fs.get_file_descriptor(fd).unwrap().lock().unwrap() {
+            INode::File { data, .. } => data.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(feature = "dedup")]
+    #[test]
+    fn identical_contents_share_one_buffer() {
+        let mut fs = SimpleFileSystem::builder().dedup(true).build();
+        let first = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let second = fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.write(first, b"same").unwrap();
+        fs.write(second, b"sa").unwrap();
+        assert!(!Arc::ptr_eq(&data_of(&fs, first), &data_of(&fs, second)));
+        fs.write(second, b"me").unwrap();
+        assert!(Arc::ptr_eq(&data_of(&fs, first), &data_of(&fs, second)));
+
+        // Writing to one of them splits it off again
+        fs.write(second, b"!").unwrap();
+        assert!(!Arc::ptr_eq(&data_of(&fs, first), &data_of(&fs, second)));
+        fs.seek(first, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(first).unwrap(), b"same");
+        fs.seek(second, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(second).unwrap(), b"same!");
+    }
+
+    #[cfg(feature = "dedup")]
+    #[test]
+    fn buffers_are_only_shared_with_dedup_on() {
+        let mut fs = SimpleFileSystem::new();
+        let first = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let second = fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.write(first, b"x").unwrap();
+        fs.write(second, b"x").unwrap();
+        assert!(!Arc::ptr_eq(&data_of(&fs, first), &data_of(&fs, second)));
+    }
+
+    fn read_file(fs: &mut SimpleFileSystem, path: &str) -> Vec<u8> {
+        let fd = fs.open(path).unwrap();
+        let data = fs.read_to_end(fd).unwrap();
+        fs.close(fd).unwrap();
+        data
+    }
+
+    fn buffer_of(fs: &SimpleFileSystem, path: &str) -> Arc<Vec<u8>> {
+        match &*fs.get_inode(path).unwrap().lock().unwrap() {
+            INode::File { data, .. } => data.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn writing_to_a_clone_leaves_the_original() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        fs.clone_cow("/a", "/b").unwrap();
+        assert!(Arc::ptr_eq(&buffer_of(&fs, "/a"), &buffer_of(&fs, "/b")));
+
+        let clone = fs.open("/b").unwrap();
+        fs.write(clone, b"J").unwrap();
+        assert_eq!(read_file(&mut fs, "/a"), b"hello");
+        assert_eq!(read_file(&mut fs, "/b"), b"Jello");
+    }
+
+    #[test]
+    fn writing_to_the_original_leaves_the_clone() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        fs.clone_cow("/a", "/c").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        fs.write(fd, b"y").unwrap();
+        assert_eq!(read_file(&mut fs, "/c"), b"hello");
+        assert_eq!(read_file(&mut fs, "/a"), b"yello");
+    }
+
+    #[test]
+    fn cloning_a_folder_keeps_links_inside_it() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/x", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"x").unwrap();
+        fs.link("/d/x", "/d/y").unwrap();
+        fs.link("/d/x", "/outside").unwrap();
+
+        fs.clone_cow("/d", "/e").unwrap();
+        assert_eq!(read_file(&mut fs, "/e/y"), b"x");
+        assert!(Arc::ptr_eq(
+            &fs.get_inode("/e/x").unwrap(),
+            &fs.get_inode("/e/y").unwrap()
+        ));
+        assert_eq!(fs.stat("/e/x").unwrap().links, 2);
+        assert!(fs.clone_cow("/d", "/e").is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn closed_files_are_stored_compressed() {
+        let mut fs = SimpleFileSystem::builder().compress(true).build();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let text = "abcdefgh".repeat(1000);
+        fs.write(fd, text.as_bytes()).unwrap();
+        // Open files stay inflated
+        assert_eq!(fs.compressed_size("/a").unwrap(), 8000);
+        fs.close(fd).unwrap();
+        assert!(fs.compressed_size("/a").unwrap() < 200);
+        assert_eq!(fs.stat("/a").unwrap().size, 8000);
+
+        // Positions stay in plain byte offsets
+        let fd = fs.open("/a").unwrap();
+        fs.seek(fd, OffsetFrom::Start(7996)).unwrap();
+        fs.write(fd, b"XYZW!").unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(fs.stat("/a").unwrap().size, 8001);
+
+        let fd = fs.open("/a").unwrap();
+        let contents = fs.read_to_string(fd).unwrap();
+        assert!(contents.starts_with("abcdefgh"));
+        assert!(contents.ends_with("XYZW!"));
+    }
+
+    #[test]
+    fn checksums_are_pinned_fnv1a() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.checksum("/a").unwrap(), 0xcbf2_9ce4_8422_2325);
+        fs.write(fd, b"a").unwrap();
+        assert_eq!(fs.checksum("/a").unwrap(), 0xaf63_dc4c_8601_ec8c);
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        fs.write(fd, b"foobar").unwrap();
+        assert_eq!(fs.checksum("/a").unwrap(), 0x8594_4171_f739_67e8);
+
+        assert!(fs.verify("/a", 0x8594_4171_f739_67e8).unwrap());
+        assert!(!fs.verify("/a", 1).unwrap());
+    }
+
+    #[test]
+    fn xattrs_can_be_set_read_listed_and_removed() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.set_xattr("/a", "user.b", b"2").unwrap();
+        fs.set_xattr("/a", "user.a", b"1").unwrap();
+        assert_eq!(fs.get_xattr("/a", "user.a").unwrap(), b"1");
+        assert_eq!(fs.list_xattr("/a").unwrap(), vec!["user.a", "user.b"]);
+
+        fs.remove_xattr("/a", "user.a").unwrap();
+        assert_eq!(fs.list_xattr("/a").unwrap(), vec!["user.b"]);
+        assert!(matches!(
+            fs.get_xattr("/a", "user.a"),
+            Err(FileSystemError::XattrNotFound)
+        ));
+        assert!(matches!(
+            fs.remove_xattr("/a", "user.a"),
+            Err(FileSystemError::XattrNotFound)
+        ));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_reads_and_writes_interleave() {
+        let fs = Arc::new(tokio::sync::Mutex::new(SimpleFileSystem::new()));
+        let fd = fs
+            .lock()
+            .await
+            .create("/a", Permissions::ReadWrite)
+            .unwrap();
+        let writer = {
+            let fs = fs.clone();
+            tokio::spawn(async move {
+                for _ in 0..100 {
+                    fs.write(fd, b"ab").await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let reader = {
+            let fs = fs.clone();
+            tokio::spawn(async move {
+                let fd = fs.open("/a").await.unwrap();
+                let mut buffer = [0; 4];
+                for _ in 0..50 {
+                    fs.seek(fd, OffsetFrom::Start(0)).await.unwrap();
+                    let bytes_read = fs.read(fd, &mut buffer).await.unwrap();
+                    // Each write lands whole, so a reader never sees half of one
+                    assert_eq!(bytes_read % 2, 0);
+                    assert_eq!(&buffer[..bytes_read], &b"abab"[..bytes_read]);
+                    tokio::task::yield_now().await;
+                }
+                fs.close(fd).await.unwrap();
+            })
+        };
+        writer.await.unwrap();
+        reader.await.unwrap();
+
+        fs.seek(fd, OffsetFrom::Start(0)).await.unwrap();
+        let mut buffer = [0; 300];
+        assert_eq!(fs.read(fd, &mut buffer).await.unwrap(), 200);
+        AsyncFileSystem::close(&*fs, fd).await.unwrap();
+    }
+
+    #[test]
+    fn buffered_writer_batches_single_bytes() {
+        use std::io::Write;
+
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        {
+            let mut writer = fs.buf_writer(fd, 64).unwrap();
+            for i in 0..10_000 {
+                writer.write_all(&[(i % 251) as u8]).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        let data = fs.read_to_end(fd).unwrap();
+        assert_eq!(data.len(), 10_000);
+        assert!(data
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == (i % 251) as u8));
+    }
+
+    #[test]
+    fn buffered_writer_writes_what_is_left_on_drop() {
+        use std::io::Write;
+
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let mut writer = fs.buf_writer(fd, 64).unwrap();
+        writer.write_all(b"tail").unwrap();
+        drop(writer);
+        assert_eq!(fs.fstat(fd).unwrap().size, 4);
+        assert!(fs.buf_writer(99, 4).is_err());
+    }
+
+    #[test]
+    fn lines_strip_newlines_and_keep_an_unterminated_last_line() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"one\ntwo\nthree").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        let lines: Vec<String> = fs.lines(fd).unwrap().map(Result::unwrap).collect();
+        assert_eq!(lines, vec!["one", "two", "three"]);
+
+        // Lines start at the descriptor's position
+        fs.seek(fd, OffsetFrom::Start(4)).unwrap();
+        assert_eq!(fs.lines(fd).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn lines_report_invalid_utf8_as_an_item() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"ok\n\xff\nafter\n").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        let lines: Vec<_> = fs.lines(fd).unwrap().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(matches!(lines[1], Err(FileSystemError::InvalidUtf8)));
+        assert_eq!(lines[2].as_deref().unwrap(), "after");
+    }
+
+    #[test]
+    fn a_write_past_the_end_zero_fills_the_gap() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write_at(fd, 5, b"Z").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), vec![0, 0, 0, 0, 0, b'Z']);
+
+        let fd = fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.seek(fd, OffsetFrom::Start(5)).unwrap();
+        fs.write(fd, b"Z").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), vec![0, 0, 0, 0, 0, b'Z']);
+    }
+
+    #[test]
+    fn statfs_summarizes_a_known_tree() {
+        let mut fs = SimpleFileSystem::new_with_quota(100);
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.mkdir("/d/e", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        fs.link("/d/a", "/b").unwrap();
+        let fd = fs.create("/c", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.symlink("/c", "/s").unwrap();
+
+        // The root counts as a folder, and a hard linked file only once
+        assert_eq!(
+            fs.statfs(),
+            FsStats {
+                files: 2,
+                directories: 3,
+                bytes_used: 8,
+                bytes_free: Some(92),
+            }
+        );
+        assert_eq!(SimpleFileSystem::new().statfs().bytes_free, None);
+    }
+
+    #[test]
+    fn the_lowest_closed_descriptor_number_is_reused() {
+        let mut fs = SimpleFileSystem::new();
+        let first = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let middle = fs.create("/b", Permissions::ReadWrite).unwrap();
+        let last = fs.create("/c", Permissions::ReadWrite).unwrap();
+        fs.close(middle).unwrap();
+        assert_eq!(fs.open("/a").unwrap(), middle);
+
+        fs.close(last).unwrap();
+        fs.close(first).unwrap();
+        assert_eq!(fs.open("/c").unwrap(), first);
+        assert_eq!(fs.open("/c").unwrap(), last);
+        assert_eq!(fs.open("/c").unwrap(), last + 1);
+    }
+
+    #[test]
+    fn exclusive_create_fails_on_anything_existing() {
+        let mut fs = SimpleFileSystem::new();
+        let exclusive = OpenFlags::CREATE | OpenFlags::EXCL | OpenFlags::WRITE;
+        let fd = fs.open_with("/lock", exclusive).unwrap();
+        fs.write(fd, b"pid").unwrap();
+        assert!(matches!(
+            fs.open_with("/lock", exclusive),
+            Err(FileSystemError::FileExists)
+        ));
+        assert_eq!(fs.stat("/lock").unwrap().size, 3);
+
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.open_with("/d", exclusive),
+            Err(FileSystemError::FileExists)
+        ));
+        // Even a dangling symlink is something in the way
+        fs.symlink("/nowhere", "/s").unwrap();
+        assert!(matches!(
+            fs.open_with("/s", exclusive),
+            Err(FileSystemError::FileExists)
+        ));
+    }
+
+    #[test]
+    fn create_with_truncate_clears_an_existing_file() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"old data").unwrap();
+
+        let flags = OpenFlags::CREATE | OpenFlags::TRUNC | OpenFlags::READ | OpenFlags::WRITE;
+        let fd = fs.open_with("/a", flags).unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().size, 0);
+        assert_eq!(fs.seek(fd, OffsetFrom::Current(0)).unwrap(), 0);
+        fs.write(fd, b"new").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"new");
+    }
+
+    #[test]
+    fn rename_moves_a_subtree() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/sub", Permissions::ReadWrite).unwrap();
+        fs.mkdir("/b", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/a/sub/f", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"kept").unwrap();
+        fs.close(fd).unwrap();
+
+        fs.rename("/a", "/b/a").unwrap();
+        assert!(!fs.exists("/a"));
+        assert_eq!(read_file(&mut fs, "/b/a/sub/f"), b"kept");
+        assert!(fs.fsck().is_empty());
+    }
+
+    #[test]
+    fn rename_rejects_moving_a_folder_into_itself() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/sub", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.rename("/a", "/a/sub/a"),
+            Err(FileSystemError::InvalidMove)
+        ));
+        assert!(fs.exists("/a/sub"));
+    }
+
+    #[test]
+    fn access_probes_permissions_without_opening() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/w", Permissions::Write).unwrap();
+        fs.create("/r", Permissions::Read).unwrap();
+
+        assert!(matches!(
+            fs.access("/w", Permissions::Read),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        fs.access("/w", Permissions::Write).unwrap();
+        assert!(matches!(
+            fs.access("/r", Permissions::Write),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        fs.access("/r", Permissions::Read).unwrap();
+        assert!(matches!(
+            fs.access("/missing", Permissions::Read),
+            Err(FileSystemError::FileNotFound)
+        ));
+        assert_eq!(fs.file_descriptors.len(), 2);
+    }
+
+    #[test]
+    fn touch_creates_an_empty_file_without_a_descriptor() {
+        let mut fs = SimpleFileSystem::with_clock(FakeClock(Arc::new(AtomicU64::new(10))));
+        fs.touch("/a").unwrap();
+
+        let metadata = fs.stat("/a").unwrap();
+        assert_eq!(metadata.size, 0);
+        assert_eq!(metadata.permissions, Permissions::ReadWrite);
+        assert!(fs.file_descriptors.is_empty());
+    }
+
+    #[test]
+    fn touch_bumps_modified_on_an_existing_file() {
+        let time = Arc::new(AtomicU64::new(10));
+        let mut fs = SimpleFileSystem::with_clock(FakeClock(time.clone()));
+        fs.touch("/a").unwrap();
+
+        time.store(50, Ordering::SeqCst);
+        fs.touch("/a").unwrap();
+        let metadata = fs.stat("/a").unwrap();
+        assert_eq!(metadata.modified, at(50));
+        assert_eq!(metadata.created, at(10));
+        assert!(fs.file_descriptors.is_empty());
+    }
+
+    #[test]
+    fn touch_needs_write_permission_on_an_existing_file() {
+        let time = Arc::new(AtomicU64::new(10));
+        let mut fs = SimpleFileSystem::with_clock(FakeClock(time.clone()));
+        fs.create("/a", Permissions::Read).unwrap();
+
+        time.store(50, Ordering::SeqCst);
+        assert!(matches!(
+            fs.touch("/a"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        assert_eq!(fs.stat("/a").unwrap().modified, at(10));
+    }
+
+    #[test]
+    fn mktemp_hands_out_distinct_names() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/tmp", Permissions::ReadWrite).unwrap();
+
+        let mut names = HashSet::new();
+        for _ in 0..5 {
+            let (path, fd) = fs.mktemp("/tmp", "scratch").unwrap();
+            assert!(path.starts_with("/tmp/scratch"));
+            fs.write(fd, b"x").unwrap();
+            assert!(names.insert(path));
+        }
+        assert_eq!(fs.list_dir("/tmp").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn subscribers_see_a_create_then_a_remove() {
+        let mut fs = SimpleFileSystem::new();
+        let events = fs.subscribe();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.close(fd).unwrap();
+        fs.unlink("/a").unwrap();
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                FsEvent::Created("/a".to_string()),
+                FsEvent::Removed("/a".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn revert_restores_the_first_of_three_writes() {
+        let mut fs = SimpleFileSystem::builder().history_depth(3).build();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        for data in [&b"one"[..], b"two", b"three"] {
+            fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+            fs.write(fd, data).unwrap();
+        }
+        fs.close(fd).unwrap();
+
+        assert_eq!(fs.history_len("/a").unwrap(), 3);
+        fs.revert("/a", 2).unwrap();
+        assert_eq!(read_file(&mut fs, "/a"), b"one");
+        assert!(matches!(
+            fs.revert("/a", 4),
+            Err(FileSystemError::VersionNotFound)
+        ));
+    }
+
+    #[test]
+    fn revert_counts_against_the_quota() {
+        let mut fs = SimpleFileSystem::builder()
+            .history_depth(2)
+            .max_total_bytes(4)
+            .build();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abcd").unwrap();
+        fs.ftruncate(fd, 1).unwrap();
+        let other = fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.write(other, b"xyz").unwrap();
+
+        assert!(matches!(
+            fs.revert("/a", 1),
+            Err(FileSystemError::QuotaExceeded)
+        ));
+        assert_eq!(read_file(&mut fs, "/a"), b"a");
+        fs.unlink("/b").unwrap();
+        fs.close(other).unwrap();
+        fs.revert("/a", 1).unwrap();
+        assert_eq!(read_file(&mut fs, "/a"), b"abcd");
+    }
+
+    #[test]
+    fn diff_reports_differing_bytes_and_extra_length() {
+        let mut fs = SimpleFileSystem::new();
+        let a = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let b = fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.write(a, b"hello world").unwrap();
+        fs.write(b, b"jello warld!!").unwrap();
+
+        assert_eq!(
+            fs.diff("/a", "/b").unwrap(),
+            FileDiff {
+                changed: vec![(0, b'h', b'j'), (7, b'o', b'a')],
+                extra_bytes: true
+            }
+        );
+        assert_eq!(
+            fs.diff("/a", "/a").unwrap(),
+            FileDiff {
+                changed: vec![],
+                extra_bytes: false
+            }
+        );
+    }
+
+    #[test]
+    fn fsck_finds_injected_problems() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.link("/a", "/b").unwrap();
+        assert!(fs.fsck().is_empty());
+
+        // Drop a name behind the file's back and slip in one with a slash
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let folder = fs.lookup(&["d".to_string()]).unwrap();
+        if let INode::Folder { contents, .. } = &mut *fs.backend.root.lock().unwrap() {
+            contents.remove("b").unwrap();
+            contents.insert("x/y".to_string(), folder);
+        }
+        let problems = fs.fsck();
+        assert!(problems.contains(&FsckProblem::InvalidName {
+            folder: "/".to_string(),
+            name: "x/y".to_string()
+        }));
+        assert!(problems.iter().any(|problem| matches!(
+            problem,
+            FsckProblem::LinkCount {
+                links: 2,
+                entries: 1,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn hard_links_share_an_inode_number() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.link("/a", "/c").unwrap();
+
+        let ino = fs.stat("/a").unwrap().ino;
+        assert_eq!(fs.stat("/c").unwrap().ino, ino);
+        assert_ne!(fs.stat("/b").unwrap().ino, ino);
+    }
+
+    #[test]
+    fn readv_reads_back_what_writev_wrote_and_moves_on() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.writev(fd, &[b"ab", b"cde", b"f"]).unwrap(), 6);
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+
+        let mut first = [0; 4];
+        let mut second = [0; 4];
+        assert_eq!(fs.readv(fd, &mut [&mut first, &mut second]).unwrap(), 6);
+        assert_eq!(&first, b"abcd");
+        assert_eq!(&second[..2], b"ef");
+        assert_eq!(fs.tell(fd).unwrap(), 6);
+    }
+
+    #[test]
+    fn readdir_walks_files_and_folders_one_at_a_time() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/d/sub", Permissions::ReadWrite).unwrap();
+        fs.create("/d/b.txt", Permissions::ReadWrite).unwrap();
+        fs.create("/d/a.txt", Permissions::ReadWrite).unwrap();
+
+        let fd = fs.open("/d").unwrap();
+        let mut entries = Vec::new();
+        while let Some(entry) = fs.readdir(fd).unwrap() {
+            entries.push(entry);
+        }
+        assert_eq!(
+            entries,
+            vec![
+                DirEntry {
+                    name: "a.txt".to_string(),
+                    is_dir: false
+                },
+                DirEntry {
+                    name: "b.txt".to_string(),
+                    is_dir: false
+                },
+                DirEntry {
+                    name: "sub".to_string(),
+                    is_dir: true
+                },
+            ]
+        );
+        assert_eq!(fs.readdir(fd).unwrap(), None);
+    }
+
+    #[test]
+    fn a_clone_is_independent_of_the_original() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let fd = fs.create("/d/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"orig").unwrap();
+
+        let mut copy = fs.clone();
+        assert!(copy.file_descriptors.is_empty());
+        let copy_fd = copy.open("/d/a").unwrap();
+        copy.write(copy_fd, b"CHANGED").unwrap();
+        copy.create("/new", Permissions::ReadWrite).unwrap();
+
+        assert_eq!(read_file(&mut fs, "/d/a"), b"orig");
+        assert!(!fs.exists("/new"));
+        assert_eq!(read_file(&mut copy, "/d/a"), b"CHANGED");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn import_dir_copies_a_host_tree() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let host = tempfile::tempdir().unwrap();
+        fs::create_dir_all(host.path().join("sub/deep")).unwrap();
+        fs::write(host.path().join("a.txt"), "hello").unwrap();
+        fs::write(host.path().join("sub/deep/b"), "bbb").unwrap();
+        fs::write(host.path().join("ro"), "r").unwrap();
+        fs::set_permissions(host.path().join("ro"), fs::Permissions::from_mode(0o444)).unwrap();
+
+        let mut fs = SimpleFileSystem::new();
+        fs.import_dir(host.path(), "/m/x", false).unwrap();
+        assert_eq!(fs.list_dir("/m/x").unwrap(), vec!["a.txt", "ro", "sub"]);
+        assert_eq!(read_file(&mut fs, "/m/x/a.txt"), b"hello");
+        assert_eq!(read_file(&mut fs, "/m/x/sub/deep/b"), b"bbb");
+        assert_eq!(fs.stat("/m/x/ro").unwrap().permissions, Permissions::Read);
+        assert_eq!(read_file(&mut fs, "/m/x/ro"), b"r");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn import_then_export_round_trips_a_host_tree() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = tempfile::tempdir().unwrap();
+        fs::create_dir_all(source.path().join("sub")).unwrap();
+        fs::write(source.path().join("a.txt"), "hello").unwrap();
+        fs::write(source.path().join("sub/b"), "bbb").unwrap();
+        fs::write(source.path().join("ro"), "r").unwrap();
+        fs::set_permissions(source.path().join("ro"), fs::Permissions::from_mode(0o444)).unwrap();
+
+        let mut fs = SimpleFileSystem::new();
+        fs.import_dir(source.path(), "/t", false).unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let out = target.path().join("out");
+        fs.export_dir("/t", &out).unwrap();
+
+        for name in ["a.txt", "sub/b", "ro"] {
+            assert_eq!(
+                fs::read(out.join(name)).unwrap(),
+                fs::read(source.path().join(name)).unwrap()
+            );
+        }
+        assert!(out.join("sub").is_dir());
+        assert!(fs::metadata(out.join("ro"))
+            .unwrap()
+            .permissions()
+            .readonly());
+        assert!(!fs::metadata(out.join("a.txt"))
+            .unwrap()
+            .permissions()
+            .readonly());
+    }
+
+    #[test]
+    fn entry_count_counts_immediate_children() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/d/sub/deeper", Permissions::ReadWrite)
+            .unwrap();
+        fs.mkdir("/d/other", Permissions::ReadWrite).unwrap();
+        for name in ["/d/a", "/d/b", "/d/c"] {
+            fs.create(name, Permissions::ReadWrite).unwrap();
+        }
+
+        assert_eq!(fs.entry_count("/d").unwrap(), 5);
+        assert!(matches!(
+            fs.entry_count("/d/a"),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn append_returns_where_each_record_starts() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/log", Permissions::ReadWrite).unwrap();
+
+        assert_eq!(fs.append(fd, b"one").unwrap(), 0);
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.append(fd, b"three").unwrap(), 3);
+        assert_eq!(fs.append(fd, b"!").unwrap(), 8);
+        fs.close(fd).unwrap();
+        assert_eq!(read_file(&mut fs, "/log"), b"onethree!");
+    }
+
+    #[test]
+    fn populate_removes_what_it_made_when_an_entry_fails() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/kept", Permissions::ReadWrite).unwrap();
+        let open = fs.create("/kept/open", Permissions::ReadWrite).unwrap();
+        let events = fs.subscribe();
+
+        let result = fs.populate(&[
+            ("/kept/a", b"a", Permissions::ReadWrite),
+            ("/new/deep/b", b"b", Permissions::Read),
+            ("/new/bad\0", b"c", Permissions::ReadWrite),
+        ]);
+        assert!(result.is_err());
+        assert!(!fs.exists("/kept/a"));
+        assert!(!fs.exists("/new"));
+        assert_eq!(fs.list_dir("/kept").unwrap(), vec!["open"]);
+
+        // Descriptors opened beforehand survive, and every creation was undone
+        fs.write(open, b"still open").unwrap();
+        let events: Vec<_> = events.try_iter().collect();
+        let created = events
+            .iter()
+            .filter(|event| matches!(event, FsEvent::Created(_)))
+            .count();
+        let removed = events
+            .iter()
+            .filter(|event| matches!(event, FsEvent::Removed(_)))
+            .count();
+        assert_eq!((created, removed), (4, 4));
+        assert!(fs.fsck().is_empty());
+    }
+
+    #[test]
+    fn peek_reads_the_same_bytes_twice() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"header body").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+
+        let mut first = [0; 6];
+        let mut second = [0; 6];
+        assert_eq!(fs.peek(fd, &mut first).unwrap(), 6);
+        assert_eq!(fs.peek(fd, &mut second).unwrap(), 6);
+        assert_eq!(first, second);
+        assert_eq!(&first, b"header");
+        assert_eq!(fs.tell(fd).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_moves_on_where_peek_stays() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abcdef").unwrap();
+
+        let mut first = [0; 3];
+        let mut second = [0; 3];
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        fs.read(fd, &mut first).unwrap();
+        fs.read(fd, &mut second).unwrap();
+        assert_eq!((&first, &second), (b"abc", b"def"));
+        assert_eq!(fs.tell(fd).unwrap(), 6);
+
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        fs.peek(fd, &mut first).unwrap();
+        fs.read(fd, &mut second).unwrap();
+        assert_eq!((&first, &second), (b"abc", b"abc"));
+        assert_eq!(fs.tell(fd).unwrap(), 3);
+    }
+
+    #[test]
+    fn intermediate_folders_inherit_their_parents_permissions() {
+        let mut fs = SimpleFileSystem::builder()
+            .inherit_permissions(true)
+            .build();
+        fs.populate(&[("/a/b/c.txt", b"c", Permissions::Read)])
+            .unwrap();
+        assert_eq!(fs.stat("/a").unwrap().permissions, Permissions::ReadWrite);
+        assert_eq!(fs.stat("/a/b").unwrap().permissions, Permissions::ReadWrite);
+        assert_eq!(
+            fs.stat("/a/b/c.txt").unwrap().permissions,
+            Permissions::Read
+        );
+
+        // Only the folders on the way inherit; the last one gets what was asked for
+        fs.create_dir_all("/a/x/y", Permissions::Read).unwrap();
+        assert_eq!(fs.stat("/a/x").unwrap().permissions, Permissions::ReadWrite);
+        assert_eq!(fs.stat("/a/x/y").unwrap().permissions, Permissions::Read);
+    }
+
+    #[test]
+    fn canonicalize_resolves_dots_and_slashes() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/b", Permissions::ReadWrite).unwrap();
+
+        assert_eq!(fs.canonicalize("/a/./b/../b").unwrap(), "/a/b");
+        assert_eq!(fs.canonicalize("//a//b/").unwrap(), "/a/b");
+        assert_eq!(fs.canonicalize("/").unwrap(), "/");
+        assert!(matches!(
+            fs.canonicalize("/a/missing"),
+            Err(FileSystemError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn canonicalize_follows_symlinks() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/a/b", Permissions::ReadWrite).unwrap();
+        fs.create("/a/b/f", Permissions::ReadWrite).unwrap();
+        fs.symlink("b", "/a/l").unwrap();
+
+        assert_eq!(fs.canonicalize("/a/l/f").unwrap(), "/a/b/f");
+        fs.chdir("/a").unwrap();
+        assert_eq!(fs.canonicalize("l").unwrap(), "/a/b");
+    }
+
+    #[test]
+    fn exists_covers_files_folders_and_missing_paths() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.create("/d/f", Permissions::ReadWrite).unwrap();
+        fs.symlink("/gone", "/dangling").unwrap();
+
+        assert!(fs.exists("/"));
+        assert!(fs.exists("/d"));
+        assert!(fs.exists("/d/f"));
+        assert!(fs.exists("/dangling"));
+        assert!(!fs.exists("/missing"));
+        assert!(!fs.exists("/d/f/below"));
+    }
+
+    #[test]
+    fn is_file_and_is_dir_tell_nodes_apart() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        fs.create("/f", Permissions::ReadWrite).unwrap();
+
+        assert!(fs.is_file("/f"));
+        assert!(!fs.is_dir("/f"));
+        assert!(fs.is_dir("/d"));
+        assert!(!fs.is_file("/d"));
+        assert!(!fs.is_file("/missing"));
+        assert!(!fs.is_dir("/missing"));
+    }
+
+    #[test]
+    fn descriptors_follow_a_renamed_file() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"one").unwrap();
+
+        fs.rename("/a", "/b").unwrap();
+        fs.write(fd, b"two").unwrap();
+        assert!(!fs.exists("/a"));
+        assert_eq!(read_file(&mut fs, "/b"), b"onetwo");
+        assert_eq!(fs.fstat(fd).unwrap().ino, fs.stat("/b").unwrap().ino);
+    }
+
+    #[test]
+    fn files_stop_at_the_per_file_limit() {
+        let mut fs = SimpleFileSystem::builder().max_file_bytes(8).build();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+
+        fs.write(fd, b"12345678").unwrap();
+        assert!(matches!(
+            fs.write(fd, b"9"),
+            Err(FileSystemError::FileTooLarge)
+        ));
+        assert_eq!(fs.fstat(fd).unwrap().size, 8);
+        assert!(matches!(
+            fs.ftruncate(fd, 9),
+            Err(FileSystemError::FileTooLarge)
+        ));
+        fs.ftruncate(fd, 2).unwrap();
+        fs.ftruncate(fd, 8).unwrap();
+
+        // The limit is per file, not shared
+        let other = fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.write(other, b"12345678").unwrap();
+    }
+
+    #[test]
+    fn format_empties_the_tree_and_resets_descriptors() {
+        let mut fs = SimpleFileSystem::builder()
+            .max_total_bytes(10)
+            .starting_fd(3)
+            .build();
+        fs.populate(&[
+            ("/a/b", b"12345", Permissions::ReadWrite),
+            ("/c", b"x", Permissions::ReadWrite),
+        ])
+        .unwrap();
+        let fd = fs.open("/c").unwrap();
+        fs.open("/a/b").unwrap();
+
+        fs.format();
+        assert!(fs.list_dir("/").unwrap().is_empty());
+        assert!(fs.file_descriptors.is_empty());
+        assert_eq!(fs.next_fd, 3);
+        assert_eq!(fs.create("/x", Permissions::ReadWrite).unwrap(), fd);
+        // The quota is kept
+        let fd = fs.open("/x").unwrap();
+        assert!(matches!(
+            fs.write(fd, &[0; 11]),
+            Err(FileSystemError::QuotaExceeded)
+        ));
+    }
+
+    #[test]
+    fn memory_usage_grows_with_written_bytes() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        let before = fs.memory_usage();
+
+        fs.write(fd, &[7; 1000]).unwrap();
+        assert_eq!(fs.memory_usage(), before + 1000);
+        assert_eq!(fs.memory_usage(), fs.memory_usage());
+    }
+
+    fn mounted_docs() -> Box<SimpleFileSystem> {
+        let mut inner = SimpleFileSystem::new();
+        inner.mkdir("/docs", Permissions::ReadWrite).unwrap();
+        let fd = inner.create("/docs/a.txt", Permissions::ReadWrite).unwrap();
+        inner.write(fd, b"inner").unwrap();
+        inner.close(fd).unwrap();
+        Box::new(inner)
+    }
+
+    #[test]
+    fn files_resolve_inside_a_mount() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/mnt", Permissions::ReadWrite).unwrap();
+        fs.create("/mnt/hidden", Permissions::ReadWrite).unwrap();
+        fs.mount_at("/mnt", mounted_docs()).unwrap();
+
+        assert_eq!(fs.list_dir("/mnt").unwrap(), vec!["docs"]);
+        assert!(fs.is_file("/mnt/docs/a.txt"));
+        assert!(!fs.exists("/mnt/hidden"));
+        let fd = fs.open("/mnt/docs/a.txt").unwrap();
+        let mut buffer = [0; 5];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer, b"inner");
+        assert!(matches!(
+            fs.rename("/mnt/docs/a.txt", "/a.txt"),
+            Err(FileSystemError::CrossDevice)
+        ));
+    }
+
+    fn layered() -> OverlayFs {
+        let mut lower = SimpleFileSystem::new();
+        lower
+            .populate(&[
+                ("/etc/conf", b"base", Permissions::ReadWrite),
+                ("/etc/keep", b"k", Permissions::Read),
+            ])
+            .unwrap();
+        OverlayFs::new(Box::new(lower), Box::new(SimpleFileSystem::new()))
+    }
+
+    fn read_overlay(fs: &mut OverlayFs, path: &str) -> Vec<u8> {
+        let fd = fs.open_with(path, OpenFlags::READ).unwrap();
+        let mut buffer = vec![0; fs.fstat(fd).unwrap().size];
+        fs.read(fd, &mut buffer).unwrap();
+        fs.close(fd).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn overlay_reads_through_to_the_lower_layer() {
+        let mut fs = layered();
+        assert_eq!(read_overlay(&mut fs, "/etc/conf"), b"base");
+        assert_eq!(fs.list_dir("/etc").unwrap(), vec!["conf", "keep"]);
+        assert!(!fs.upper.exists("/etc"));
+    }
+
+    #[test]
+    fn overlay_copies_a_file_up_before_writing() {
+        let mut fs = layered();
+        let fd = fs.open("/etc/conf").unwrap();
+        fs.seek(fd, OffsetFrom::End(0)).unwrap();
+        fs.write(fd, b"+up").unwrap();
+        fs.close(fd).unwrap();
+
+        assert_eq!(read_overlay(&mut fs, "/etc/conf"), b"base+up");
+        assert!(fs.upper.is_file("/etc/conf"));
+        assert_eq!(fs.lower.stat("/etc/conf").unwrap().size, 4);
+    }
+
+    #[test]
+    fn overlay_whiteouts_mask_lower_entries() {
+        let mut fs = layered();
+        fs.unlink("/etc/conf").unwrap();
+
+        assert!(!fs.exists("/etc/conf"));
+        assert_eq!(fs.list_dir("/etc").unwrap(), vec!["keep"]);
+        assert!(fs.lower.exists("/etc/conf"));
+        assert!(matches!(
+            fs.open("/etc/conf"),
+            Err(FileSystemError::FileNotFound)
+        ));
+        // A new file at that name starts out empty
+        let fd = fs.create("/etc/conf", Permissions::ReadWrite).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read_overlay(&mut fs, "/etc/conf"), b"");
+    }
+
+    #[test]
+    fn tell_follows_writes_and_seeks() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        assert_eq!(fs.tell(fd).unwrap(), 0);
+
+        fs.write(fd, b"hello").unwrap();
+        assert_eq!(fs.tell(fd).unwrap(), 5);
+        fs.seek(fd, OffsetFrom::Start(2)).unwrap();
+        assert_eq!(fs.tell(fd).unwrap(), 2);
+        assert!(matches!(
+            fs.tell(fd + 1),
+            Err(FileSystemError::InvalidFileDescriptor)
+        ));
+    }
+
+    #[test]
+    fn reads_near_usize_max_do_not_overflow() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+
+        fs.seek(fd, OffsetFrom::Start(usize::MAX - 1)).unwrap();
+        let mut buffer = [0; 8];
+        assert_eq!(fs.read(fd, &mut buffer).unwrap(), 0);
+        assert_eq!(fs.read_at(fd, usize::MAX, &mut buffer).unwrap(), 0);
+        assert_eq!(fs.read_at(fd, 1, &mut buffer).unwrap(), 2);
+        assert_eq!(&buffer[..2], b"bc");
+    }
+
+    #[test]
+    fn directory_handles_match_paths_from_the_root() {
+        let mut fs = SimpleFileSystem::new();
+        let deep = "/a/b/c/d/e/f/g/h";
+        fs.create_dir_all(deep, Permissions::ReadWrite).unwrap();
+        let dir = fs.open_dir(deep).unwrap();
+
+        for i in 0..200 {
+            let fd = fs
+                .create_in(&dir, &format!("f{i}"), Permissions::ReadWrite)
+                .unwrap();
+            fs.write(fd, format!("v{i}").as_bytes()).unwrap();
+            fs.close(fd).unwrap();
+        }
+        for i in 0..200 {
+            let by_handle = fs.open_in(&dir, &format!("f{i}")).unwrap();
+            let by_path = fs.open(&format!("{deep}/f{i}")).unwrap();
+            assert_eq!(
+                fs.read_to_end(by_handle).unwrap(),
+                fs.read_to_end(by_path).unwrap()
+            );
+            assert_eq!(
+                fs.fstat(by_handle).unwrap().ino,
+                fs.fstat(by_path).unwrap().ino
+            );
+            fs.close(by_handle).unwrap();
+            fs.close(by_path).unwrap();
+        }
+        assert_eq!(fs.entry_count(deep).unwrap(), 200);
+    }
+
+    #[test]
+    fn create_in_checks_what_create_checks() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        let dir = fs.open_dir("/d").unwrap();
+        let events = fs.subscribe();
+        fs.create_in(&dir, "a", Permissions::ReadWrite).unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            FsEvent::Created("/d/a".to_string())
+        );
+        assert!(matches!(
+            fs.create_in(&dir, "a", Permissions::ReadWrite),
+            Err(FileSystemError::FileExists)
+        ));
+        assert!(matches!(
+            fs.create_in(&dir, &"x".repeat(MAX_NAME_LEN + 1), Permissions::ReadWrite),
+            Err(FileSystemError::InvalidName)
+        ));
+
+        fs.chmod("/d", Permissions::Read).unwrap();
+        assert!(matches!(
+            fs.create_in(&dir, "b", Permissions::ReadWrite),
+            Err(FileSystemError::PermissionDenied)
+        ));
+
+        // Once the folder is gone, or a file took its place, the handle adds nothing
+        fs.chmod("/d", Permissions::ReadWrite).unwrap();
+        fs.remove_dir_all("/d").unwrap();
+        assert!(matches!(
+            fs.create_in(&dir, "b", Permissions::ReadWrite),
+            Err(FileSystemError::FileNotFound)
+        ));
+        fs.create("/d", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.create_in(&dir, "b", Permissions::ReadWrite),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn read_version_at_gives_the_contents_as_of_a_time() {
+        let time = Arc::new(AtomicU64::new(10));
+        let mut fs = SimpleFileSystem::with_clock(FakeClock(time.clone()));
+        fs.history_depth = 10;
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        for (seconds, data) in [(10, b"one"), (20, b"two"), (30, b"333")] {
+            time.store(seconds, Ordering::SeqCst);
+            fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+            fs.write(fd, data).unwrap();
+        }
+
+        assert_eq!(fs.read_version_at("/a", at(25)).unwrap(), b"two");
+        assert_eq!(fs.read_version_at("/a", at(20)).unwrap(), b"two");
+        assert_eq!(fs.read_version_at("/a", at(99)).unwrap(), b"333");
+    }
+
+    #[test]
+    fn compact_gives_back_space_a_truncated_file_held() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, &[1; 10000]).unwrap();
+        fs.ftruncate(fd, 10).unwrap();
+
+        let before = fs.memory_usage();
+        assert!(fs.compact() > 0);
+        assert!(fs.memory_usage() < before);
+        assert_eq!(fs.compact(), 0);
+        fs.close(fd).unwrap();
+        assert_eq!(read_file(&mut fs, "/a"), [1; 10]);
+    }
+
+    #[test]
+    fn reopen_points_a_descriptor_at_another_file() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"aaaa").unwrap();
+        let other = fs.create("/b", Permissions::ReadWrite).unwrap();
+        fs.write(other, b"bee").unwrap();
+        fs.close(other).unwrap();
+
+        fs.reopen(fd, "/b").unwrap();
+        assert_eq!(fs.tell(fd).unwrap(), 0);
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"bee");
+        fs.mkdir("/d", Permissions::ReadWrite).unwrap();
+        assert!(matches!(
+            fs.reopen(fd, "/d"),
+            Err(FileSystemError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn handles_follow_descriptor_access() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/a", Permissions::ReadWrite).unwrap();
+        let read_only = fs.open_with("/a", OpenFlags::READ).unwrap();
+        assert!(matches!(
+            fs.writer(read_only),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        let write_only = fs.open_with("/a", OpenFlags::WRITE).unwrap();
+        assert!(matches!(
+            fs.reader(write_only),
+            Err(FileSystemError::PermissionDenied)
+        ));
+
+        let append = fs
+            .open_with("/a", OpenFlags::WRITE | OpenFlags::APPEND)
+            .unwrap();
+        let mut handle = fs.writer(append).unwrap();
+        handle.write_all(b"ab").unwrap();
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        handle.write_all(b"c").unwrap();
+        assert!(handle.seek(SeekFrom::Current(-4)).is_err());
+        assert_eq!(fs.read_to_end(read_only).unwrap(), b"abc");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn loading_hard_links_recounts_their_links() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"01234").unwrap();
+        fs.link("/a", "/b").unwrap();
+        let mut saved = Vec::new();
+        fs.save_to_writer(&mut saved).unwrap();
+
+        let mut loaded = SimpleFileSystem::load_from_reader(&saved[..]).unwrap();
+        assert_eq!(loaded.stat("/a").unwrap().links, 1);
+        assert!(loaded.fsck().is_empty());
+        loaded.unlink("/a").unwrap();
+        loaded.unlink("/b").unwrap();
+        assert_eq!(loaded.total_bytes.load(Ordering::SeqCst), 0);
+        assert!(loaded.fsck().is_empty());
+    }
+
+    #[test]
+    fn renaming_to_another_case_changes_the_stored_name() {
+        let mut fs = SimpleFileSystem::builder().case_insensitive(true).build();
+        let fd = fs.create("/Foo", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"x").unwrap();
+        fs.rename("/Foo", "/foo").unwrap();
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["foo"]);
+        let fd = fs.open("/FOO").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"x");
+
+        // Hard links under different names stay as they are
+        fs.link("/foo", "/bar").unwrap();
+        fs.rename("/foo", "/BAR").unwrap();
+        assert_eq!(fs.list_dir("/").unwrap(), vec!["bar", "foo"]);
+    }
+
+    #[test]
+    fn xattrs_of_unreadable_files_cannot_be_read_or_listed() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.set_xattr("/a", "user.a", b"1").unwrap();
+        fs.chmod("/a", Permissions::Write).unwrap();
+        assert!(matches!(
+            fs.get_xattr("/a", "user.a"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+        assert!(matches!(
+            fs.list_xattr("/a"),
+            Err(FileSystemError::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn an_empty_write_past_the_end_leaves_the_size() {
+        let mut fs = SimpleFileSystem::new();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.seek(fd, OffsetFrom::Start(10)).unwrap();
+        fs.write(fd, b"").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().size, 0);
+        fs.write_at(fd, 20, b"").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().size, 0);
+        fs.write_all(fd, b"").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().size, 0);
+    }
+
+    #[test]
+    fn rename_rejects_moving_a_folder_into_itself_through_a_symlink() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/a", Permissions::ReadWrite).unwrap();
+        fs.create("/a/f", Permissions::ReadWrite).unwrap();
+        fs.symlink("/a", "/link").unwrap();
+        assert!(matches!(
+            fs.rename("/a", "/link/b"),
+            Err(FileSystemError::InvalidMove)
+        ));
+        assert!(fs.exists("/a/f"));
+        assert!(fs.fsck().is_empty());
+    }
+
+    #[test]
+    fn helpers_built_on_the_trait_reach_mounted_descriptors() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/mnt", Permissions::ReadWrite).unwrap();
+        fs.mount_at("/mnt", mounted_docs()).unwrap();
+        let fd = fs.open("/mnt/docs/a.txt").unwrap();
+
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"inner");
+        assert_eq!(fs.append(fd, b"!").unwrap(), 5);
+        fs.write_all(fd, b"?").unwrap();
+        fs.seek(fd, OffsetFrom::Start(0)).unwrap();
+        assert_eq!(fs.read_to_string(fd).unwrap(), "inner!?");
+    }
+
+    #[test]
+    fn node_level_operations_refuse_mounted_descriptors() {
+        let mut fs = SimpleFileSystem::new();
+        fs.mkdir("/mnt", Permissions::ReadWrite).unwrap();
+        fs.create("/own", Permissions::ReadWrite).unwrap();
+        fs.mount_at("/mnt", mounted_docs()).unwrap();
+        let fd = fs.open("/mnt/docs/a.txt").unwrap();
+
+        let cross = |result: Result<(), FileSystemError>| {
+            matches!(result, Err(FileSystemError::CrossDevice))
+        };
+        assert!(cross(fs.read_at(fd, 0, &mut [0; 1]).map(|_| ())));
+        assert!(cross(fs.write_at(fd, 0, b"x")));
+        assert!(cross(fs.dup(fd).map(|_| ())));
+        assert!(cross(fs.reopen(fd, "/own")));
+        assert!(cross(fs.readdir(fd).map(|_| ())));
+        assert!(cross(fs.lock(fd, true)));
+        assert!(cross(fs.reader(fd).map(|_| ())));
+        // The descriptor itself is still fine
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"inner");
+    }
+
+    #[test]
+    fn folders_holding_a_mount_point_stay_put() {
+        let mut fs = SimpleFileSystem::new();
+        fs.create_dir_all("/p/m", Permissions::ReadWrite).unwrap();
+        fs.mount_at("/p/m", mounted_docs()).unwrap();
+
+        assert!(matches!(
+            fs.rename("/p", "/q"),
+            Err(FileSystemError::CrossDevice)
+        ));
+        assert!(matches!(
+            fs.remove_dir_all("/p"),
+            Err(FileSystemError::CrossDevice)
+        ));
+        fs.symlink("/p", "/link").unwrap();
+        assert!(matches!(
+            fs.remove_dir_all("/link/m"),
+            Err(FileSystemError::CrossDevice)
+        ));
+        assert!(fs.exists("/p/m/docs/a.txt"));
+        assert!(!fs.exists("/q"));
+    }
+
+    #[test]
+    fn read_version_at_needs_read_permission() {
+        let mut fs = SimpleFileSystem::builder().history_depth(3).build();
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"secret").unwrap();
+        fs.chmod("/a", Permissions::Write).unwrap();
+        assert!(matches!(
+            fs.read_version_at("/a", SystemTime::UNIX_EPOCH),
+            Err(FileSystemError::PermissionDenied)
+        ));
+    }
+
+    // Backend keeping its tree in a MemoryBackend while recording every entry put in or
+    // taken out, so tests can see the generic layer reach storage through the trait
+    struct RecordingBackend {
+        inner: MemoryBackend,
+        changes: Vec<String>,
+    }
+
+    impl StorageBackend for RecordingBackend {
+        fn resolve(
+            &self,
+            components: &[String],
+            follow_last: bool,
+        ) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+            self.inner.resolve(components, follow_last)
+        }
+
+        fn insert(
+            &mut self,
+            components: &[String],
+            inode: Arc<Mutex<INode>>,
+        ) -> Result<(), FileSystemError> {
+            self.changes.push(format!("insert {}", path_of(components)));
+            self.inner.insert(components, inode)
+        }
+
+        fn remove(
+            &mut self,
+            components: &[String],
+            check: &dyn Fn(&INode) -> Result<(), FileSystemError>,
+        ) -> Result<Arc<Mutex<INode>>, FileSystemError> {
+            self.changes.push(format!("remove {}", path_of(components)));
+            self.inner.remove(components, check)
+        }
+
+        fn list(&self, components: &[String]) -> Result<Vec<String>, FileSystemError> {
+            self.inner.list(components)
+        }
+
+        fn entry_name(&self, components: &[String]) -> Result<String, FileSystemError> {
+            self.inner.entry_name(components)
+        }
+
+        fn entry_key<'a>(
+            &self,
+            contents: &'a HashMap<String, Arc<Mutex<INode>>>,
+            name: &str,
+        ) -> Option<&'a String> {
+            self.inner.entry_key(contents, name)
+        }
+
+        fn set_case_insensitive(&mut self, case_insensitive: bool) {
+            self.inner.set_case_insensitive(case_insensitive);
+        }
+
+        fn root(&self) -> Arc<Mutex<INode>> {
+            self.inner.root()
+        }
+
+        fn set_root(&mut self, root: Arc<Mutex<INode>>) {
+            self.changes.push("set root".to_string());
+            self.inner.set_root(root);
+        }
+
+        fn with_root(&self, root: Arc<Mutex<INode>>) -> Self {
+            RecordingBackend {
+                inner: self.inner.with_root(root),
+                changes: Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn a_custom_backend_sees_every_change() {
+        let backend = RecordingBackend {
+            inner: MemoryBackend::new(SystemTime::now()),
+            changes: Vec::new(),
+        };
+        let mut fs = SimpleFileSystem::with_backend(backend, SystemClock);
+        let fd = fs.create("/a", Permissions::ReadWrite).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.rename("/a", "/b").unwrap();
+        let fd = fs.open("/b").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"abc");
+
+        let snapshot = fs.snapshot();
+        fs.unlink("/b").unwrap();
+        assert!(!fs.exists("/b"));
+        fs.restore(snapshot);
+        assert_eq!(
+            fs.backend.changes,
+            [
+                "insert /a",
+                "remove /a",
+                "insert /b",
+                "remove /b",
+                "set root"
+            ]
+        );
+        let fd = fs.open("/b").unwrap();
+        assert_eq!(fs.read_to_end(fd).unwrap(), b"abc");
     }
 }
\ No newline at end of file